@@ -1,47 +1,189 @@
 use rubook_lib::models::{Book, Response};
 
-use std::{collections::HashMap, env};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    env, fmt,
+};
 
 use dotenvy::dotenv;
-use inquire::Text;
+use inquire::{MultiSelect, Select, Text};
+use reqwest::Url;
 
-pub async fn book_search() -> Result<HashMap<String, Book>, Box<dyn std::error::Error>> {
+const VOLUMES_URL: &str = "https://www.googleapis.com/books/v1/volumes";
+/// Results per page in the menu, Google Books returns at most 40
+pub const PAGE_SIZE: u32 = 20;
+pub const MAX_PAGE_SIZE: u32 = 40;
+
+fn google_api_key() -> String {
     dotenv().ok();
-    let google_api_key = env::var("GOOGLE_API_KEY").expect("GOOGLE_API_KEY not set");
-
-    let mut books = HashMap::new();
-
-    let book_query = Text::new("Search for a book:").prompt();
-
-    match book_query {
-        Ok(book_query) => {
-            println!("Searching for: {}", book_query.trim());
-
-            let url = format!(
-                "https://www.googleapis.com/books/v1/volumes?q={}&key={}",
-                book_query, google_api_key
-            );
-
-            let response_text = reqwest::get(&url).await?.text().await?;
-            let response = serde_json::from_str::<Response>(&response_text);
-
-            match response {
-                Ok(response) => {
-                    if let Some(error) = response.error {
-                        println!("Serialization error from google api: {}", error.message);
-                    } else if let Some(items) = response.items {
-                        for book in items {
-                            books.insert(book.id.clone(), book.clone());
-                        }
-                    } else {
-                        println!("No items found");
-                    }
-                }
-                Err(e) => println!("Serialization error: {}", e),
+    env::var("GOOGLE_API_KEY").expect("GOOGLE_API_KEY not set")
+}
+
+/// One page of Google Books search results.
+#[derive(Debug, Clone)]
+pub struct SearchPage {
+    pub books: Vec<Book>,
+    pub start_index: u32,
+    /// The `maxResults` the page was requested with
+    pub page_size: u32,
+    pub total_items: u32,
+}
+
+impl SearchPage {
+    pub fn has_next(&self) -> bool {
+        !self.books.is_empty() && self.start_index + self.page_size < self.total_items
+    }
+
+    pub fn has_previous(&self) -> bool {
+        self.start_index > 0
+    }
+}
+
+enum PageOption {
+    NextPage,
+    PreviousPage,
+    Add(usize),
+    Cancel,
+}
+
+impl fmt::Display for PageOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PageOption::NextPage => write!(f, "Next page"),
+            PageOption::PreviousPage => write!(f, "Previous page"),
+            PageOption::Add(1) => write!(f, "Add the selected book to your collection"),
+            PageOption::Add(count) => {
+                write!(f, "Add the {} selected books to your collection", count)
             }
+            PageOption::Cancel => write!(f, "Cancel"),
         }
-        Err(e) => println!("Error: {}", e),
     }
+}
+
+/// Asks for a query and lets the user page through the results, picking books on any page.
+pub async fn book_search() -> Result<Vec<Book>, Box<dyn std::error::Error>> {
+    let book_query = match Text::new("Search for a book:").prompt() {
+        Ok(book_query) => book_query,
+        Err(e) => {
+            println!("Error: {}", e);
+            return Ok(Vec::new());
+        }
+    };
+
+    let mut pages: HashMap<u32, SearchPage> = HashMap::new();
+    let mut selected: Vec<Book> = Vec::new();
+    let mut start_index = 0;
+    loop {
+        if let Entry::Vacant(entry) = pages.entry(start_index) {
+            entry.insert(search_page(&book_query, start_index, PAGE_SIZE).await?);
+        }
+        let page = &pages[&start_index];
+        if page.books.is_empty() {
+            if page.has_previous() {
+                // NOTE:(akotro) Google's total is an estimate, the last page can turn out empty
+                eprintln!("No more results");
+                start_index = start_index.saturating_sub(PAGE_SIZE);
+                continue;
+            }
+            eprintln!("No items found");
+            return Ok(selected);
+        }
+
+        let defaults = page
+            .books
+            .iter()
+            .enumerate()
+            .filter(|(_, book)| selected.iter().any(|selected| selected.id == book.id))
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        let message = format!(
+            "Select books to add to your collection ({}-{} of about {}):",
+            page.start_index + 1,
+            page.start_index + page.books.len() as u32,
+            page.total_items
+                .max(page.start_index + page.books.len() as u32)
+        );
+        let page_selection = MultiSelect::new(&message, page.books.clone())
+            .with_default(&defaults)
+            .prompt()?;
+
+        selected.retain(|book| !page.books.iter().any(|page_book| page_book.id == book.id));
+        selected.extend(page_selection);
+
+        let mut options = Vec::new();
+        if page.has_next() {
+            options.push(PageOption::NextPage);
+        }
+        if page.has_previous() {
+            options.push(PageOption::PreviousPage);
+        }
+        if !selected.is_empty() {
+            options.push(PageOption::Add(selected.len()));
+        }
+        options.push(PageOption::Cancel);
+
+        match Select::new("What next?", options).prompt()? {
+            PageOption::NextPage => start_index += PAGE_SIZE,
+            PageOption::PreviousPage => start_index = start_index.saturating_sub(PAGE_SIZE),
+            PageOption::Add(_) => return Ok(selected),
+            PageOption::Cancel => return Ok(Vec::new()),
+        }
+    }
+}
+
+/// Results `start_index` to `start_index + max_results` of a search, in Google's order.
+pub async fn search_page(
+    book_query: &str,
+    start_index: u32,
+    max_results: u32,
+) -> Result<SearchPage, Box<dyn std::error::Error>> {
+    let google_api_key = google_api_key();
+
+    eprintln!("Searching for: {}", book_query.trim());
+
+    let page_size = max_results.clamp(1, MAX_PAGE_SIZE);
+    let url = Url::parse_with_params(
+        VOLUMES_URL,
+        &[
+            ("q", book_query.trim()),
+            ("startIndex", &start_index.to_string()),
+            ("maxResults", &page_size.to_string()),
+            ("key", &google_api_key),
+        ],
+    )?;
+
+    let response_text = reqwest::get(url).await?.text().await?;
+    let response = serde_json::from_str::<Response>(&response_text)?;
+    if let Some(error) = response.error {
+        return Err(format!("Google Books error: {}", error.message).into());
+    }
+
+    let mut books = response.items.unwrap_or_default();
+    // NOTE:(akotro) Google repeats a book now and then, within a page too
+    let mut seen = Vec::new();
+    books.retain(|book| {
+        let new = !seen.contains(&book.id);
+        seen.push(book.id.clone());
+        new
+    });
+
+    Ok(SearchPage {
+        books,
+        start_index,
+        page_size,
+        total_items: response.total_items.unwrap_or_default(),
+    })
+}
+
+pub async fn get_book(book_id: &str) -> Result<Book, Box<dyn std::error::Error>> {
+    let google_api_key = google_api_key();
+
+    let mut url = Url::parse(VOLUMES_URL)?;
+    url.path_segments_mut()
+        .map_err(|_| "Invalid Google Books url")?
+        .push(book_id);
+    url.query_pairs_mut().append_pair("key", &google_api_key);
 
-    Ok(books)
+    let response = reqwest::get(url).await?.error_for_status()?;
+    Ok(response.json::<Book>().await?)
 }
@@ -0,0 +1,236 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    book_util::{MAX_PAGE_SIZE, PAGE_SIZE},
+    output::OutputFormat,
+};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "rubook",
+    version,
+    about = "Search for books, manage your collection and download them as ebooks",
+    long_about = "Search for books, manage your collection and download them as ebooks.\n\n\
+                  Run without a subcommand to start the interactive menu."
+)]
+pub struct Cli {
+    /// Username used to log in to the backend
+    #[arg(long, short, global = true, env = "RUBOOK_USERNAME")]
+    pub username: Option<String>,
+
+    /// Password used to log in to the backend
+    #[arg(
+        long,
+        short,
+        global = true,
+        env = "RUBOOK_PASSWORD",
+        hide_env_values = true
+    )]
+    pub password: Option<String>,
+
+    /// Url of the rubook backend, e.g. https://example.com:9595/rubook
+    #[arg(long, global = true, env = "RUBOOK_BACKEND_URL")]
+    pub backend_url: Option<String>,
+
+    /// PEM encoded CA certificate to trust for the backend connection
+    #[arg(long, global = true, env = "RUBOOK_CA_CERT")]
+    pub ca_cert: Option<PathBuf>,
+
+    /// Only accept a backend certificate with this SHA-256 fingerprint (hex)
+    #[arg(long, global = true, env = "RUBOOK_CERT_FINGERPRINT")]
+    pub cert_fingerprint: Option<String>,
+
+    /// Don't verify the backend certificate at all
+    #[arg(long, global = true)]
+    pub insecure: bool,
+
+    /// Output format of the command results
+    #[arg(long, short, global = true, value_enum, default_value_t = OutputFormat::Plain)]
+    pub output: OutputFormat,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Log in to the backend and remember the session
+    Login,
+    /// End the stored session on the backend and forget it
+    Logout,
+    /// Search for books on Google Books
+    Search {
+        /// Search terms, e.g. a title and an author
+        #[arg(required = true, num_args = 1..)]
+        query: Vec<String>,
+
+        /// Page of results to show, starting from 1
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        page: u32,
+
+        /// Number of results per page, at most 40
+        #[arg(
+            long,
+            default_value_t = PAGE_SIZE,
+            value_parser = clap::value_parser!(u32).range(1..=MAX_PAGE_SIZE as i64)
+        )]
+        page_size: u32,
+    },
+    /// View and manage your collection
+    #[command(subcommand)]
+    Collection(CollectionCommand),
+    /// Download a book from your collection
+    Download(DownloadArgs),
+    /// Download book covers into the cover cache and show where they are
+    Covers(CoversArgs),
+    /// View the libgen mirrors
+    #[command(subcommand)]
+    Mirrors(MirrorsCommand),
+    /// View and check the books you have downloaded
+    #[command(subcommand)]
+    Library(LibraryCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CollectionCommand {
+    /// List the books in your collection
+    List,
+    /// Add books to your collection by their Google Books id
+    Add {
+        #[arg(required = true, num_args = 1..)]
+        book_ids: Vec<String>,
+    },
+    /// Remove books from your collection by their Google Books id
+    Remove {
+        #[arg(required = true, num_args = 1..)]
+        book_ids: Vec<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct DownloadArgs {
+    /// Google Books id of a book in your collection
+    pub book_id: String,
+
+    /// Search the fiction catalogue instead of non-fiction
+    #[arg(long)]
+    pub fiction: bool,
+
+    /// Download the libgen result with this md5 instead of the first one
+    #[arg(long)]
+    pub md5: Option<String>,
+
+    /// Host url of the search mirror to try first, the other working ones are used if it fails
+    #[arg(long)]
+    pub search_mirror: Option<String>,
+
+    /// Host url of the download mirror to try first, the other working ones are used if it fails
+    #[arg(long)]
+    pub download_mirror: Option<String>,
+
+    /// Leave the metadata of a downloaded epub as the uploader wrote it
+    #[arg(long)]
+    pub keep_metadata: bool,
+
+    /// Embed the cover into a downloaded epub
+    #[arg(long, conflicts_with = "keep_metadata")]
+    pub embed_cover: bool,
+
+    /// Save the book in this directory instead of the configured one
+    #[arg(long, value_name = "DIR", env = "RUBOOK_LIBRARY_DIR")]
+    pub library_dir: Option<PathBuf>,
+
+    /// Name the file with this template instead of the configured one, e.g. "{author}/{title}.{ext}"
+    #[arg(long, value_name = "TEMPLATE")]
+    pub name_template: Option<String>,
+
+    /// Download the book even if it has been downloaded before
+    #[arg(long)]
+    pub force: bool,
+
+    #[command(flatten)]
+    pub search: SearchOptionsArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct CoversArgs {
+    /// Google Books ids of the books, your whole collection when left out
+    pub book_ids: Vec<String>,
+
+    /// Copy the covers into this directory, named after the book id
+    #[arg(long, value_name = "DIR")]
+    pub export: Option<PathBuf>,
+}
+
+/// Overrides for the search options saved in the config.
+#[derive(Debug, Args)]
+pub struct SearchOptionsArgs {
+    /// Only keep results in this language, can be repeated. `any` keeps every language
+    #[arg(long = "language", value_name = "LANGUAGE")]
+    pub languages: Vec<String>,
+
+    /// Prefer results with this extension, e.g. epub. Can be repeated, most preferred first
+    #[arg(long = "extension", value_name = "EXTENSION")]
+    pub extensions: Vec<String>,
+
+    /// Only keep results published in or after this year
+    #[arg(long)]
+    pub year_from: Option<u32>,
+
+    /// Only keep results published in or before this year
+    #[arg(long)]
+    pub year_to: Option<u32>,
+
+    /// Only keep results up to this size, in megabytes
+    #[arg(long, value_name = "MB", value_parser = megabytes)]
+    pub max_size: Option<f64>,
+
+    /// Results per search page
+    #[arg(long, value_parser = results_per_page)]
+    pub results: Option<u32>,
+
+    /// Save these search options as the defaults
+    #[arg(long)]
+    pub save_search_options: bool,
+}
+
+fn results_per_page(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(results @ (25 | 50 | 100)) => Ok(results),
+        _ => Err(String::from("must be 25, 50 or 100")),
+    }
+}
+
+fn megabytes(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(megabytes) if megabytes.is_finite() && megabytes >= 0.0 => Ok(megabytes),
+        _ => Err(String::from("must be a size in megabytes, 0 or more")),
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MirrorsCommand {
+    /// List the search and download mirrors
+    List {
+        /// Check each mirror and show whether it's reachable or blocked and its latency,
+        /// fastest first
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum LibraryCommand {
+    /// List the downloaded books and where their files are
+    List,
+    /// Check that the downloaded files are still there and unchanged
+    Verify,
+    /// Find moved files and forget the ones that were deleted
+    Rescan {
+        /// Directory to look for moved files in, the configured library directory by default
+        #[arg(long, value_name = "DIR", env = "RUBOOK_LIBRARY_DIR")]
+        library_dir: Option<PathBuf>,
+    },
+}
@@ -0,0 +1,490 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use inquire::{InquireError, Password, PasswordDisplayMode, Text};
+use reqwest::Client;
+use rubook_lib::{
+    backend_util::BackendClient,
+    libgen::{
+        failover::{search_fiction_failover, search_non_fiction_failover, with_preferred},
+        mirrors::{Mirror, MirrorList, MirrorType, HEALTH_CHECK_TIMEOUT},
+        search::SearchOptions,
+    },
+    libgen_util::{await_working_mirrors, cover_urls, download_failover, embed_metadata, Progress},
+    library::{DownloadTarget, LibraryOptions},
+    library_index::FileStatus,
+    models::Book,
+    user::User,
+};
+
+use crate::{
+    cli::{
+        Cli, CollectionCommand, Command, CoversArgs, DownloadArgs, LibraryCommand, MirrorsCommand,
+    },
+    config::{self, Config},
+    output::{
+        print_item, print_list, CoverEntry, DownloadEntry, MirrorEntry, MirrorHealthEntry,
+        OutputFormat, RescanEntry, UserEntry, VerifyEntry,
+    },
+    session,
+};
+
+pub async fn run(
+    client: Arc<Client>,
+    backend: Option<BackendClient>,
+    mut config: Config,
+    cli: Cli,
+    command: Command,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let output = cli.output;
+
+    match command {
+        Command::Login => {
+            let backend = config::require_backend(backend)?;
+            let user = login(&backend, &cli).await?;
+            print_item(output, &UserEntry::from(&user));
+        }
+        Command::Logout => {
+            let backend = config::require_backend(backend)?;
+            if session::logout(&backend).await? {
+                eprintln!("Logged out");
+            } else {
+                eprintln!("Not logged in");
+            }
+        }
+        Command::Search {
+            query,
+            page,
+            page_size,
+        } => search(&query.join(" "), page, page_size, output).await?,
+        Command::Collection(collection_command) => {
+            let backend = config::require_backend(backend)?;
+            let mut user = authenticate(&backend, &cli).await?;
+            collection(&backend, &mut user, collection_command, output).await?;
+        }
+        Command::Download(args) => {
+            let options = config.search_options(&args.search)?;
+            if args.search.save_search_options {
+                config.search = options.clone();
+                config::save(&config)?;
+                eprintln!("Saved search options");
+            }
+
+            let library = config.library_options(&args);
+            let backend = config::require_backend(backend)?;
+            let user = authenticate(&backend, &cli).await?;
+            download(&client, &backend, &user, args, &options, &library, output).await?;
+        }
+        Command::Covers(args) => covers(&client, backend, &cli, args, output).await?,
+        Command::Mirrors(MirrorsCommand::List { check }) => {
+            let backend = config::require_backend(backend)?;
+            // NOTE:(akotro) Logging in gives the backend client its tokens
+            authenticate(&backend, &cli).await?;
+            list_mirrors(&client, &backend, check, output).await?;
+        }
+        Command::Library(library_command) => library(&config, library_command, output)?,
+    }
+
+    Ok(())
+}
+
+async fn authenticate(
+    backend: &BackendClient,
+    cli: &Cli,
+) -> Result<User, Box<dyn std::error::Error>> {
+    let stored_session = session::load();
+    let session_matches = match (&stored_session, &cli.username) {
+        (Some(stored_session), Some(username)) => &stored_session.username == username,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+    if session_matches {
+        if let Some(user) = session::restore_user(backend).await {
+            return Ok(user);
+        }
+    }
+
+    login(backend, cli).await
+}
+
+async fn login(backend: &BackendClient, cli: &Cli) -> Result<User, Box<dyn std::error::Error>> {
+    let username = match &cli.username {
+        Some(username) => username.clone(),
+        None => Text::new("Enter your username:")
+            .prompt()
+            .map_err(missing_credentials)?,
+    };
+    let password = match &cli.password {
+        Some(password) => password.clone(),
+        None => Password::new("Enter your password: ")
+            .with_display_mode(PasswordDisplayMode::Masked)
+            .without_confirmation()
+            .prompt()
+            .map_err(missing_credentials)?,
+    };
+
+    let user = backend.login_user(username, password).await?;
+    session::remember(backend, &user);
+
+    Ok(user)
+}
+
+fn missing_credentials(error: InquireError) -> String {
+    format!(
+        "Missing credentials ({}), pass --username and --password \
+         or set RUBOOK_USERNAME and RUBOOK_PASSWORD",
+        error
+    )
+}
+
+async fn search(
+    query: &str,
+    page: u32,
+    page_size: u32,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let start_index = (page - 1) * page_size;
+    let page = crate::book_util::search_page(query, start_index, page_size).await?;
+    if page.books.is_empty() {
+        eprintln!("No items found");
+    } else {
+        eprintln!(
+            "Results {}-{} of about {}",
+            start_index + 1,
+            start_index + page.books.len() as u32,
+            page.total_items
+        );
+    }
+    print_list(output, &page.books);
+
+    Ok(())
+}
+
+async fn collection(
+    backend: &BackendClient,
+    user: &mut User,
+    command: CollectionCommand,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        CollectionCommand::List => print_list(output, &user.collection),
+        CollectionCommand::Add { book_ids } => {
+            let mut added_books = Vec::new();
+            for book_id in book_ids {
+                if user.collection.iter().any(|book| book.id == book_id) {
+                    eprintln!("{} is already in your collection", book_id);
+                    continue;
+                }
+
+                let book = crate::book_util::get_book(&book_id).await?;
+                backend.create_book(&book, &user.id).await?;
+                user.collection.push(book.clone());
+                added_books.push(book);
+            }
+            print_list(output, &added_books);
+        }
+        CollectionCommand::Remove { book_ids } => {
+            let mut removed_books = Vec::new();
+            for book_id in book_ids {
+                let book = user
+                    .collection
+                    .iter()
+                    .find(|book| book.id == book_id)
+                    .cloned()
+                    .ok_or_else(|| format!("{} is not in your collection", book_id))?;
+
+                backend.delete_book(&user.id, book_id.clone()).await?;
+                user.collection.retain(|book| book.id != book_id);
+                removed_books.push(book);
+            }
+            print_list(output, &removed_books);
+        }
+    }
+
+    Ok(())
+}
+
+async fn download(
+    client: &Arc<Client>,
+    backend: &BackendClient,
+    user: &User,
+    args: DownloadArgs,
+    options: &SearchOptions,
+    library: &LibraryOptions,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let book = user
+        .collection
+        .iter()
+        .find(|book| book.id == args.book_id)
+        .ok_or_else(|| format!("{} is not in your collection", args.book_id))?;
+
+    let index = config::open_library_index()?;
+    if !args.force {
+        if let Some(entry) = index.downloaded(&book.id)? {
+            eprintln!(
+                "Already downloaded to {}, pass --force to download it again",
+                entry.file_path.display()
+            );
+            return Ok(());
+        }
+    }
+
+    let mirrors = backend.get_mirrors().await?;
+    let mirror_checks = Arc::new(MirrorList::new(mirrors)).spawn_health_checks(client);
+    let (working_search_mirrors, working_download_mirrors) =
+        await_working_mirrors(&mirror_checks).await?;
+
+    let search_mirrors = rank_mirrors(&working_search_mirrors, args.search_mirror.as_deref())?;
+    let download_mirrors =
+        rank_mirrors(&working_download_mirrors, args.download_mirror.as_deref())?;
+
+    let (books, _) = if args.fiction {
+        search_fiction_failover(book, &search_mirrors, client, options).await?
+    } else {
+        search_non_fiction_failover(book, &search_mirrors, client, options).await?
+    };
+    let libgen_book = match &args.md5 {
+        Some(md5) => books.iter().find(|b| b.md5.eq_ignore_ascii_case(md5)),
+        None => books.first(),
+    }
+    .ok_or("No books were found")?;
+    let target = DownloadTarget {
+        libgen_book,
+        book: Some(book),
+        library,
+    };
+    let result = download_failover(client, &download_mirrors, target, Progress::Inline).await?;
+
+    // NOTE:(akotro) The file is saved already, a missing cover mustn't keep it out of the index
+    let cover = if args.embed_cover && !args.keep_metadata {
+        match config::open_cover_cache() {
+            // NOTE:(akotro) Libgen's covers are larger than the Google Books thumbnails
+            Ok(mut cover_cache) => {
+                cover_cache
+                    .fetch_first(client, cover_urls(libgen_book, book))
+                    .await
+            }
+            Err(e) => {
+                eprintln!("The cover can't be embedded: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    if !args.keep_metadata {
+        embed_metadata(&result, &book.volume_info, cover.as_deref());
+    }
+    if let Err(e) = index.record(&book.id, &result) {
+        eprintln!("Couldn't add {} to the library index: {}", book.id, e);
+    }
+    print_item(
+        output,
+        &DownloadEntry {
+            book_id: book.id.clone(),
+            result,
+            cover: cover.map(|path| path.display().to_string()),
+        },
+    );
+
+    Ok(())
+}
+
+async fn covers(
+    client: &Client,
+    backend: Option<BackendClient>,
+    cli: &Cli,
+    args: CoversArgs,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let books = if args.book_ids.is_empty() {
+        let backend = config::require_backend(backend)?;
+        authenticate(&backend, cli).await?.collection
+    } else {
+        let mut books = Vec::new();
+        for book_id in &args.book_ids {
+            books.push(crate::book_util::get_book(book_id).await?);
+        }
+        books
+    };
+
+    let books_with_covers = books
+        .iter()
+        .filter_map(|book| match book.volume_info.cover_url() {
+            Some(url) => Some((book, url)),
+            None => {
+                eprintln!("{} has no cover", book.id);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    let urls = books_with_covers
+        .iter()
+        .map(|(_, url)| *url)
+        .collect::<Vec<_>>();
+
+    let mut cache = config::open_cover_cache()?;
+    let paths = cache.fetch_all(client, &urls).await;
+
+    let mut entries = Vec::new();
+    for ((book, url), path) in books_with_covers.into_iter().zip(paths) {
+        let path = match (path, &args.export) {
+            (Ok(path), Some(export_dir)) => export_cover(book, &path, export_dir)?,
+            (Ok(path), None) => path,
+            (Err(e), _) => {
+                eprintln!("Couldn't get the cover of {}: {}", book.id, e);
+                continue;
+            }
+        };
+        entries.push(CoverEntry {
+            book_id: book.id.clone(),
+            title: book.volume_info.title.clone().unwrap_or_default(),
+            url: url.to_string(),
+            path: path.display().to_string(),
+        });
+    }
+    print_list(output, &entries);
+
+    Ok(())
+}
+
+fn export_cover(
+    book: &Book,
+    cached_path: &Path,
+    export_dir: &Path,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    fs::create_dir_all(export_dir)?;
+    let extension = cached_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("jpg");
+    let export_path = export_dir.join(format!("{}.{}", book.id, extension));
+    fs::copy(cached_path, &export_path)?;
+
+    Ok(export_path)
+}
+
+/// Orders the working mirrors for failover, with the one asked for by host url first.
+fn rank_mirrors(
+    mirrors: &[Mirror],
+    host_url: Option<&str>,
+) -> Result<Vec<Mirror>, Box<dyn std::error::Error>> {
+    match host_url {
+        Some(host_url) => mirrors
+            .iter()
+            .find(|mirror| mirror.host_url.trim_end_matches('/') == host_url.trim_end_matches('/'))
+            .map(|preferred| with_preferred(mirrors, preferred))
+            .ok_or_else(|| format!("Mirror {} is not available", host_url).into()),
+        None => Ok(mirrors.to_vec()),
+    }
+}
+
+async fn list_mirrors(
+    client: &Arc<Client>,
+    backend: &BackendClient,
+    check: bool,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mirrors = backend.get_mirrors().await?;
+    let mirror_list = MirrorList::new(mirrors);
+
+    if check {
+        let (search_health, download_health) = tokio::join!(
+            mirror_list.check_mirrors(MirrorType::Search, client, HEALTH_CHECK_TIMEOUT),
+            mirror_list.check_mirrors(MirrorType::Download, client, HEALTH_CHECK_TIMEOUT),
+        );
+        let entries = search_health
+            .into_iter()
+            .map(|health| MirrorHealthEntry {
+                mirror_type: "search",
+                health,
+            })
+            .chain(download_health.into_iter().map(|health| MirrorHealthEntry {
+                mirror_type: "download",
+                health,
+            }))
+            .collect::<Vec<_>>();
+        print_list(output, &entries);
+        return Ok(());
+    }
+
+    let entries = mirror_list
+        .search_mirrors
+        .into_iter()
+        .map(|mirror| MirrorEntry {
+            mirror_type: "search",
+            mirror,
+        })
+        .chain(
+            mirror_list
+                .download_mirrors
+                .into_iter()
+                .map(|mirror| MirrorEntry {
+                    mirror_type: "download",
+                    mirror,
+                }),
+        )
+        .collect::<Vec<_>>();
+    print_list(output, &entries);
+
+    Ok(())
+}
+
+fn library(
+    config: &Config,
+    command: LibraryCommand,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let index = config::open_library_index()?;
+
+    match command {
+        LibraryCommand::List => print_list(output, &index.entries()?),
+        LibraryCommand::Verify => {
+            let entries = index
+                .verify()?
+                .into_iter()
+                .map(|(entry, status)| VerifyEntry { status, entry })
+                .collect::<Vec<_>>();
+            print_list(output, &entries);
+
+            let broken = entries
+                .iter()
+                .filter(|entry| entry.status != FileStatus::Present)
+                .count();
+            if broken > 0 {
+                return Err(format!(
+                    "{} of {} files are missing or changed, `rubook library rescan` updates the index",
+                    broken,
+                    entries.len()
+                )
+                .into());
+            }
+        }
+        LibraryCommand::Rescan { library_dir } => {
+            let dir = library_dir.unwrap_or_else(|| config.library.directory());
+            let report = index.rescan(&dir)?;
+            let changes = [
+                ("relinked", report.relinked),
+                ("updated", report.updated),
+                ("removed", report.removed),
+            ];
+            let entries = changes
+                .into_iter()
+                .flat_map(|(change, entries)| {
+                    entries
+                        .into_iter()
+                        .map(move |entry| RescanEntry { change, entry })
+                })
+                .collect::<Vec<_>>();
+            eprintln!("{} entries changed", entries.len());
+            print_list(output, &entries);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::{fs, io, path::PathBuf};
+
+use rubook_lib::{
+    backend_util::{BackendClient, TlsTrust},
+    covers::CoverCache,
+    libgen::search::SearchOptions,
+    library::LibraryOptions,
+    library_index::LibraryIndex,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{Cli, DownloadArgs, SearchOptionsArgs};
+
+const CONFIG_FILE: &str = "config.json";
+const MISSING_BACKEND_URL: &str = "No backend url set, pass --backend-url, set \
+    RUBOOK_BACKEND_URL or add \"backend_url\" to config.json";
+
+/// Settings read from `config.json` in the rubook config directory.
+/// Command line flags and environment variables take precedence over them.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub backend_url: Option<String>,
+    pub ca_cert: Option<PathBuf>,
+    pub cert_fingerprint: Option<String>,
+    pub insecure: bool,
+    /// Default filters for libgen searches
+    pub search: SearchOptions,
+    /// Where downloads are saved and how they are named
+    pub library: LibraryOptions,
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("rubook"))
+}
+
+/// Where downloaded covers are kept, e.g. `~/.cache/rubook/covers`.
+pub fn cover_cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("rubook").join("covers"))
+}
+
+/// The index of downloaded books, e.g. `~/.local/share/rubook/library.sqlite3`.
+pub fn library_index_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("rubook").join("library.sqlite3"))
+}
+
+/// The backend client, failing when no backend url is configured.
+pub fn require_backend(
+    backend: Option<BackendClient>,
+) -> Result<BackendClient, Box<dyn std::error::Error>> {
+    backend.ok_or_else(|| MISSING_BACKEND_URL.into())
+}
+
+pub fn open_cover_cache() -> Result<CoverCache, Box<dyn std::error::Error>> {
+    let dir = cover_cache_dir().ok_or("No cache directory found")?;
+    Ok(CoverCache::open(dir)?)
+}
+
+pub fn open_library_index() -> Result<LibraryIndex, Box<dyn std::error::Error>> {
+    let path = library_index_path().ok_or("No data directory found")?;
+    Ok(LibraryIndex::open(&path)?)
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(CONFIG_FILE))
+}
+
+pub fn load() -> Config {
+    let contents = match config_path().and_then(|path| fs::read_to_string(path).ok()) {
+        Some(contents) => contents,
+        None => return Config::default(),
+    };
+
+    serde_json::from_str(&contents).unwrap_or_else(|e| {
+        eprintln!("Ignoring invalid {}: {}", CONFIG_FILE, e);
+        Config::default()
+    })
+}
+
+pub fn save(config: &Config) -> io::Result<()> {
+    let path = config_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory found"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(config)?)
+}
+
+impl Config {
+    /// There's no default backend, it has to be configured.
+    pub fn backend_url(&self, cli: &Cli) -> Option<String> {
+        cli.backend_url
+            .clone()
+            .or_else(|| self.backend_url.clone())
+            .filter(|backend_url| !backend_url.trim().is_empty())
+    }
+
+    pub fn tls_trust(&self, cli: &Cli) -> TlsTrust {
+        if cli.insecure {
+            TlsTrust::Insecure
+        } else if let Some(fingerprint) = &cli.cert_fingerprint {
+            TlsTrust::Fingerprint(fingerprint.clone())
+        } else if let Some(ca_cert) = &cli.ca_cert {
+            TlsTrust::CaCertificate(ca_cert.clone())
+        } else if let Some(fingerprint) = &self.cert_fingerprint {
+            TlsTrust::Fingerprint(fingerprint.clone())
+        } else if let Some(ca_cert) = &self.ca_cert {
+            TlsTrust::CaCertificate(ca_cert.clone())
+        } else if self.insecure {
+            TlsTrust::Insecure
+        } else {
+            TlsTrust::System
+        }
+    }
+
+    /// The saved search options, with the ones given on the command line taking precedence.
+    pub fn search_options(
+        &self,
+        args: &SearchOptionsArgs,
+    ) -> Result<SearchOptions, Box<dyn std::error::Error>> {
+        let mut options = self.search.clone();
+        if !args.languages.is_empty() {
+            options.languages = args
+                .languages
+                .iter()
+                .filter(|language| !language.eq_ignore_ascii_case("any"))
+                .cloned()
+                .collect();
+        }
+        if !args.extensions.is_empty() {
+            options.extensions = args.extensions.clone();
+        }
+        if args.year_from.is_some() {
+            options.year_from = args.year_from;
+        }
+        if args.year_to.is_some() {
+            options.year_to = args.year_to;
+        }
+        if let Some(max_size) = args.max_size {
+            options.max_size = Some((max_size * 1024.0 * 1024.0) as u64);
+        }
+        if let Some(results) = args.results {
+            options.results = results;
+        }
+        // NOTE:(akotro) One of the years can come from the saved options
+        if let (Some(year_from), Some(year_to)) = (options.year_from, options.year_to) {
+            if year_from > year_to {
+                return Err(format!(
+                    "The year range is empty, --year-from {} is after --year-to {}",
+                    year_from, year_to
+                )
+                .into());
+            }
+        }
+        Ok(options)
+    }
+
+    /// The library settings, with the directory and template given on the command line
+    /// taking precedence.
+    pub fn library_options(&self, args: &DownloadArgs) -> LibraryOptions {
+        let mut library = self.library.clone();
+        if args.library_dir.is_some() {
+            library.directory = args.library_dir.clone();
+        }
+        if args.name_template.is_some() {
+            library.template = args.name_template.clone();
+        }
+        library
+    }
+}
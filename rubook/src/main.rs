@@ -1,12 +1,53 @@
 mod book_util;
+mod cli;
+mod commands;
+mod config;
 mod menu;
+mod output;
+mod session;
+
+use clap::Parser;
+use rubook_lib::backend_util::{BackendClient, TlsTrust};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cli = cli::Cli::parse();
+
+    let config = config::load();
+
+    let tls_trust = config.tls_trust(&cli);
+    if tls_trust == TlsTrust::Insecure {
+        eprintln!("WARNING: The backend certificate will not be verified");
+    }
+    // NOTE:(akotro) Only commands that talk to the backend need its url
+    let backend = match config.backend_url(&cli) {
+        Some(backend_url) => {
+            let mut backend = BackendClient::new(&backend_url, &tls_trust)?;
+            backend.on_tokens_refreshed(session::update_tokens);
+            Some(backend)
+        }
+        None => None,
+    };
+
     let client = reqwest::Client::builder()
-        .danger_accept_invalid_certs(true)
         .build()
         .expect("Could not build reqwest client");
     let client = std::sync::Arc::new(client);
-    menu::main_loop(client).await
+
+    match cli.command.take() {
+        Some(command) => {
+            if let Err(e) = commands::run(client, backend, config, cli, command).await {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        None => match config::require_backend(backend) {
+            Ok(backend) => menu::main_loop(client, backend, config).await,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+    }
 }
@@ -1,9 +1,15 @@
-use std::sync::Arc;
+use std::{str::FromStr, sync::Arc};
 
+use inquire::{validator::Validation, InquireError, Select, Text};
 use reqwest::Client;
 use rubook_lib::{
-    backend_util::{delete_user, get_mirrors},
-    user::{login, register, User}, libgen::mirrors::{MirrorList, Mirror},
+    backend_util::BackendClient,
+    user::{login, register, User}, libgen::{mirrors::{MirrorList, Mirror}, search::SearchOptions},
+};
+
+use crate::{
+    config::{self, Config},
+    session,
 };
 
 #[derive(Debug)]
@@ -29,6 +35,8 @@ pub enum MainMenuOption {
     ViewCollection,
     DeleteBooks,
     DownloadBook,
+    SearchPreferences,
+    AccountSettings,
     DeleteAccount,
     ReturnToLogin,
     Exit,
@@ -40,9 +48,11 @@ impl std::fmt::Display for MainMenuOption {
             MainMenuOption::SearchForBook => write!(f, "Search for a book"),
             MainMenuOption::ViewCollection => write!(f, "View your collection"),
             MainMenuOption::DeleteBooks => write!(f, "Delete books from your collection"),
-            MainMenuOption::DownloadBook => write!(f, "Download a book from your collection"),
+            MainMenuOption::DownloadBook => write!(f, "Download books from your collection"),
+            MainMenuOption::SearchPreferences => write!(f, "Change your search preferences"),
+            MainMenuOption::AccountSettings => write!(f, "Account settings"),
             MainMenuOption::DeleteAccount => write!(f, "Delete your account"),
-            MainMenuOption::ReturnToLogin => write!(f, "Return to login menu"),
+            MainMenuOption::ReturnToLogin => write!(f, "Log out and return to login menu"),
             MainMenuOption::Exit => write!(f, "Exit"),
         }
     }
@@ -62,7 +72,77 @@ fn confirm(message: &str) -> bool {
     }
 }
 
-pub async fn main_loop(client: Arc<Client>) -> Result<(), Box<dyn std::error::Error>> {
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn prompt_optional<T: FromStr + ToString>(
+    message: &str,
+    current: Option<T>,
+) -> Result<Option<T>, InquireError> {
+    let current = current.map(|value| value.to_string()).unwrap_or_default();
+    let answer = Text::new(message)
+        .with_default(&current)
+        .with_validator(|input: &str| {
+            if input.trim().is_empty() || input.trim().parse::<T>().is_ok() {
+                Ok(Validation::Valid)
+            } else {
+                Ok(Validation::Invalid("Enter a number or leave it empty".into()))
+            }
+        })
+        .prompt()?;
+    Ok(answer.trim().parse::<T>().ok())
+}
+
+fn edit_search_options(options: &SearchOptions) -> Result<SearchOptions, InquireError> {
+    let languages = Text::new("Languages to keep, comma separated (empty keeps every language):")
+        .with_default(&options.languages.join(", "))
+        .prompt()?;
+    let extensions = Text::new("Preferred extensions, most preferred first, comma separated:")
+        .with_default(&options.extensions.join(", "))
+        .prompt()?;
+    let (year_from, year_to) = loop {
+        let year_from = prompt_optional("Published in or after:", options.year_from)?;
+        let year_to = prompt_optional("Published in or before:", options.year_to)?;
+        match (year_from, year_to) {
+            (Some(from), Some(to)) if from > to => {
+                eprintln!("The first year can't be after the last one")
+            }
+            _ => break (year_from, year_to),
+        }
+    };
+    let max_size = prompt_optional(
+        "Largest file in megabytes:",
+        options.max_size.map(|size| size / (1024 * 1024)),
+    )?;
+    let result_counts = vec![25, 50, 100];
+    let cursor = result_counts
+        .iter()
+        .position(|count| *count == options.results)
+        .unwrap_or(0);
+    let results = Select::new("Results per search:", result_counts)
+        .with_starting_cursor(cursor)
+        .prompt()?;
+
+    Ok(SearchOptions {
+        languages: split_list(&languages),
+        extensions: split_list(&extensions),
+        year_from,
+        year_to,
+        max_size: max_size.map(|size: u64| size * 1024 * 1024),
+        results,
+    })
+}
+
+pub async fn main_loop(
+    client: Arc<Client>,
+    backend: BackendClient,
+    mut config: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
     let mut exit_program = false;
 
     loop {
@@ -70,9 +150,17 @@ pub async fn main_loop(client: Arc<Client>) -> Result<(), Box<dyn std::error::Er
             break;
         }
 
-        if let Some(mut user) = login_menu(&client).await {
-            let mirrors = get_mirrors(&client, &user.token).await?;
-            if main_menu(client.clone(), &mut user, mirrors).await? {
+        let user = match session::restore_user(&backend).await {
+            Some(user) => {
+                println!("Welcome back, {}", user.username);
+                Some(user)
+            }
+            None => login_menu(&backend).await,
+        };
+
+        if let Some(mut user) = user {
+            let mirrors = backend.get_mirrors().await?;
+            if main_menu(client.clone(), &backend, &mut user, mirrors, &mut config).await? {
                 exit_program = true;
             }
         } else if confirm("Do you really want to exit?") {
@@ -84,7 +172,7 @@ pub async fn main_loop(client: Arc<Client>) -> Result<(), Box<dyn std::error::Er
     Ok(())
 }
 
-pub async fn login_menu(client: &Arc<Client>) -> Option<User> {
+pub async fn login_menu(backend: &BackendClient) -> Option<User> {
     loop {
         let options = vec![
             LoginMenuOption::Login,
@@ -98,18 +186,28 @@ pub async fn login_menu(client: &Arc<Client>) -> Option<User> {
                 LoginMenuOption::Exit => break None,
                 LoginMenuOption::Login => {
                     loop {
-                        if let Some(user) = login(client).await {
-                            return Some(user);
-                        } else if !confirm("Try again?") {
+                        match login(backend).await {
+                            Ok(user) => {
+                                session::remember(backend, &user);
+                                return Some(user);
+                            }
+                            Err(e) => eprintln!("{}", e),
+                        }
+                        if !confirm("Try again?") {
                             break None::<User>;
                         }
                     }
                 }
                 LoginMenuOption::Register => {
                     loop {
-                        if let Some(user) = register(client).await {
-                            return Some(user);
-                        } else if !confirm("Try again?") {
+                        match register(backend).await {
+                            Ok(user) => {
+                                session::remember(backend, &user);
+                                return Some(user);
+                            }
+                            Err(e) => eprintln!("{}", e),
+                        }
+                        if !confirm("Try again?") {
                             break None::<User>;
                         }
                     }
@@ -122,20 +220,22 @@ pub async fn login_menu(client: &Arc<Client>) -> Option<User> {
 
 pub async fn main_menu(
     client: Arc<Client>,
+    backend: &BackendClient,
     user: &mut User,
     mirrors: Vec<Mirror>,
+    config: &mut Config,
 ) -> Result<bool, Box<dyn std::error::Error>> {
     loop {
         let mirror_list = MirrorList::new(mirrors.clone());
-        let mut mirror_handles = std::sync::Arc::new(mirror_list)
-            .spawn_get_working_mirrors_tasks(&client)
-            .await;
+        let mirror_checks = std::sync::Arc::new(mirror_list).spawn_health_checks(&client);
 
         let options = vec![
             MainMenuOption::SearchForBook,
             MainMenuOption::ViewCollection,
             MainMenuOption::DownloadBook,
+            MainMenuOption::SearchPreferences,
             MainMenuOption::DeleteBooks,
+            MainMenuOption::AccountSettings,
             MainMenuOption::DeleteAccount,
             MainMenuOption::ReturnToLogin,
             MainMenuOption::Exit,
@@ -156,34 +256,73 @@ pub async fn main_menu(
                 }
                 MainMenuOption::ViewCollection => println!("{}", user),
                 MainMenuOption::SearchForBook => {
-                    if let Ok(books) = crate::book_util::book_search().await {
-                        if let Err(e) = user.add_books(&client, books).await {
-                            eprintln!("Error adding books: {}", e);
+                    match crate::book_util::book_search().await {
+                        Ok(books) if books.is_empty() => {}
+                        Ok(books) => {
+                            if let Err(e) = user.add_books(backend, books).await {
+                                eprintln!("Error adding books: {}", e);
+                            }
                         }
-                    } else {
-                        eprintln!("Error searching for books");
+                        Err(e) => eprintln!("Error searching for books: {}", e),
                     }
                 }
                 MainMenuOption::DeleteBooks => {
-                    if let Err(e) = user.delete_books(&client).await {
+                    if let Err(e) = user.delete_books(backend).await {
                         eprintln!("Error deleting books: {}", e);
                     }
                 }
-                MainMenuOption::DownloadBook => {
-                    if let Err(e) = user.download_books(&client, &mut mirror_handles).await {
-                        eprintln!("Error downloading books: {}", e);
+                MainMenuOption::DownloadBook => match config::open_library_index() {
+                    Ok(index) => {
+                        let mut covers = config::open_cover_cache()
+                            .map_err(|e| eprintln!("Covers can't be embedded: {}", e))
+                            .ok();
+                        if let Err(e) = user
+                            .download_books(
+                                &client,
+                                &mirror_checks,
+                                &config.search,
+                                &config.library,
+                                &index,
+                                covers.as_mut(),
+                            )
+                            .await
+                        {
+                            eprintln!("Error downloading books: {}", e);
+                        }
                     }
-                }
+                    Err(e) => eprintln!("Error opening the library index: {}", e),
+                },
+                MainMenuOption::SearchPreferences => match edit_search_options(&config.search) {
+                    Ok(options) => {
+                        config.search = options;
+                        match config::save(config) {
+                            Ok(()) => println!("Search preferences saved"),
+                            Err(e) => eprintln!("Error saving search preferences: {}", e),
+                        }
+                    }
+                    Err(e) => eprintln!("Error: {}", e),
+                },
+                MainMenuOption::AccountSettings => match user.account_settings(backend).await {
+                    Ok(()) => session::remember(backend, user),
+                    Err(e) => eprintln!("Error updating your account: {}", e),
+                },
                 MainMenuOption::DeleteAccount => {
                     if confirm("Do you really want to delete your account?") {
-                        if let Err(e) = delete_user(&client, &user.token, &user.id).await {
+                        if let Err(e) = backend.delete_user(&user.id).await {
                             eprintln!("Error: {}", e);
                         }
+                        session::clear().ok();
                         *user = User::default();
                         break;
                     }
                 }
-                MainMenuOption::ReturnToLogin => return Ok(false)
+                MainMenuOption::ReturnToLogin => {
+                    if let Err(e) = backend.logout().await {
+                        eprintln!("Couldn't end the session on the backend: {}", e);
+                    }
+                    session::clear().ok();
+                    return Ok(false);
+                }
             },
             Err(e) => eprintln!("Error: {}", e),
         }
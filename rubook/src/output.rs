@@ -0,0 +1,292 @@
+use clap::ValueEnum;
+use rubook_lib::{
+    libgen::{
+        mirrors::{Mirror, MirrorHealth},
+        models::DownloadResult,
+    },
+    library_index::{FileStatus, LibraryEntry},
+    models::Book,
+    user::User,
+};
+use serde::Serialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// One tab separated record per line
+    #[default]
+    Plain,
+    /// Aligned columns with a header row
+    Table,
+    /// A single JSON document
+    Json,
+}
+
+pub trait Render: Serialize {
+    fn headers() -> &'static [&'static str];
+    fn row(&self) -> Vec<String>;
+}
+
+pub fn print_list<T: Render>(format: OutputFormat, items: &[T]) {
+    match format {
+        OutputFormat::Json => print_json(&items),
+        OutputFormat::Plain => {
+            for item in items {
+                println!("{}", item.row().join("\t"));
+            }
+        }
+        OutputFormat::Table => print_table(T::headers(), items.iter().map(T::row).collect()),
+    }
+}
+
+pub fn print_item<T: Render>(format: OutputFormat, item: &T) {
+    match format {
+        OutputFormat::Json => print_json(item),
+        _ => print_list(format, std::slice::from_ref(item)),
+    }
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+}
+
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers
+        .iter()
+        .map(|header| header.chars().count())
+        .collect();
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |cells: Vec<String>| {
+        cells
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    println!(
+        "{}",
+        format_row(headers.iter().map(|header| header.to_string()).collect())
+    );
+    for row in rows {
+        println!("{}", format_row(row));
+    }
+}
+
+impl Render for Book {
+    fn headers() -> &'static [&'static str] {
+        &["ID", "TITLE", "AUTHORS", "PUBLISHER", "PUBLISHED"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        let volume_info = &self.volume_info;
+        vec![
+            self.id.clone(),
+            volume_info.title.clone().unwrap_or_default(),
+            volume_info.authors.clone().unwrap_or_default().join(", "),
+            volume_info.publisher.clone().unwrap_or_default(),
+            volume_info.published_date.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserEntry {
+    pub id: String,
+    pub username: String,
+}
+
+impl From<&User> for UserEntry {
+    fn from(user: &User) -> Self {
+        UserEntry {
+            id: user.id.clone(),
+            username: user.username.clone(),
+        }
+    }
+}
+
+impl Render for UserEntry {
+    fn headers() -> &'static [&'static str] {
+        &["ID", "USERNAME"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![self.id.clone(), self.username.clone()]
+    }
+}
+
+#[derive(Serialize)]
+pub struct MirrorEntry {
+    #[serde(rename = "type")]
+    pub mirror_type: &'static str,
+    #[serde(flatten)]
+    pub mirror: Mirror,
+}
+
+impl Render for MirrorEntry {
+    fn headers() -> &'static [&'static str] {
+        &["TYPE", "HOST"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![self.mirror_type.to_string(), self.mirror.host_url.clone()]
+    }
+}
+
+#[derive(Serialize)]
+pub struct MirrorHealthEntry {
+    #[serde(rename = "type")]
+    pub mirror_type: &'static str,
+    #[serde(flatten)]
+    pub health: MirrorHealth,
+}
+
+impl Render for MirrorHealthEntry {
+    fn headers() -> &'static [&'static str] {
+        &["TYPE", "HOST", "STATE", "STATUS", "LATENCY"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        let state = if self.health.blocked {
+            "blocked"
+        } else if self.health.is_working() {
+            "reachable"
+        } else if self.health.reachable {
+            "failing"
+        } else {
+            "unreachable"
+        };
+        vec![
+            self.mirror_type.to_string(),
+            self.health.mirror.host_url.clone(),
+            state.to_string(),
+            self.health
+                .status
+                .map(|status| status.to_string())
+                .unwrap_or_default(),
+            self.health
+                .latency
+                .map(|latency| format!("{}ms", latency.as_millis()))
+                .unwrap_or_default(),
+        ]
+    }
+}
+
+#[derive(Serialize)]
+pub struct DownloadEntry {
+    pub book_id: String,
+    #[serde(flatten)]
+    pub result: DownloadResult,
+    /// Cached cover of the book, libgen's or else the Google Books one
+    pub cover: Option<String>,
+}
+
+impl Render for DownloadEntry {
+    fn headers() -> &'static [&'static str] {
+        &["BOOK", "MD5", "MIRROR", "PATH", "COVER"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.book_id.clone(),
+            self.result.md5.clone(),
+            self.result.mirror.clone(),
+            self.result.file_path.clone(),
+            self.cover.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+#[derive(Serialize)]
+pub struct CoverEntry {
+    pub book_id: String,
+    pub title: String,
+    pub url: String,
+    pub path: String,
+}
+
+impl Render for CoverEntry {
+    fn headers() -> &'static [&'static str] {
+        &["BOOK", "TITLE", "PATH"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![self.book_id.clone(), self.title.clone(), self.path.clone()]
+    }
+}
+
+impl Render for LibraryEntry {
+    fn headers() -> &'static [&'static str] {
+        &["BOOK", "MD5", "SIZE", "DOWNLOADED", "PATH"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.book_id.clone(),
+            self.md5.clone(),
+            self.size.to_string(),
+            self.downloaded_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+            self.file_path.display().to_string(),
+        ]
+    }
+}
+
+#[derive(Serialize)]
+pub struct VerifyEntry {
+    pub status: FileStatus,
+    #[serde(flatten)]
+    pub entry: LibraryEntry,
+}
+
+impl Render for VerifyEntry {
+    fn headers() -> &'static [&'static str] {
+        &["STATUS", "BOOK", "MD5", "PATH"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        let status = match self.status {
+            FileStatus::Present => "present",
+            FileStatus::Missing => "missing",
+            FileStatus::Changed => "changed",
+        };
+        vec![
+            status.to_string(),
+            self.entry.book_id.clone(),
+            self.entry.md5.clone(),
+            self.entry.file_path.display().to_string(),
+        ]
+    }
+}
+
+#[derive(Serialize)]
+pub struct RescanEntry {
+    /// `relinked`, `updated` or `removed`
+    pub change: &'static str,
+    #[serde(flatten)]
+    pub entry: LibraryEntry,
+}
+
+impl Render for RescanEntry {
+    fn headers() -> &'static [&'static str] {
+        &["CHANGE", "BOOK", "MD5", "PATH"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.change.to_string(),
+            self.entry.book_id.clone(),
+            self.entry.md5.clone(),
+            self.entry.file_path.display().to_string(),
+        ]
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rubook_lib::{
+    backend_util::BackendClient,
+    models::Book,
+    user::{AuthTokens, User, UserClaims},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::config_dir;
+
+const SESSION_FILE: &str = "session.json";
+
+// NOTE:(akotro) Treat tokens that expire within this many seconds as already expired,
+// so a command doesn't fail halfway through
+const EXPIRY_LEEWAY_SECS: usize = 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub backend_url: String,
+    pub user_id: String,
+    pub username: String,
+    pub token: String,
+    /// Gets a new access token when `token` expires, sessions from older versions have none
+    #[serde(default)]
+    pub refresh_token: String,
+}
+
+impl Session {
+    pub fn new(backend: &BackendClient, user: &User) -> Session {
+        Session {
+            backend_url: backend.base_url().to_string(),
+            user_id: user.id.clone(),
+            username: user.username.clone(),
+            token: user.token.clone(),
+            refresh_token: user.refresh_token.clone(),
+        }
+    }
+
+    pub fn tokens(&self) -> AuthTokens {
+        AuthTokens {
+            token: self.token.clone(),
+            refresh_token: self.refresh_token.clone(),
+        }
+    }
+
+    pub fn claims(&self) -> Option<UserClaims> {
+        let payload = self.token.split('.').nth(1)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+        serde_json::from_slice(&payload).ok()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs() as usize;
+        match self.claims() {
+            Some(claims) => claims.exp <= now + EXPIRY_LEEWAY_SECS,
+            None => true,
+        }
+    }
+
+    /// Turns the session back into a logged in user, fetching the collection from the backend.
+    /// An expired access token is refreshed when the session has a refresh token.
+    ///
+    /// `None` when the session is no longer valid: it's of another backend or the backend
+    /// rejects it. Errors are failures to ask the backend, the session may still be valid.
+    pub async fn restore(&self, backend: &BackendClient) -> rubook_lib::Result<Option<User>> {
+        if self.backend_url != backend.base_url()
+            || (self.is_expired() && self.refresh_token.is_empty())
+        {
+            return Ok(None);
+        }
+
+        backend.set_tokens(Some(self.tokens()));
+        let collection = match self.fetch_collection(backend).await {
+            Ok(collection) => collection,
+            Err(e) => {
+                backend.set_tokens(None);
+                return if e.is_unauthorized() {
+                    Ok(None)
+                } else {
+                    Err(e)
+                };
+            }
+        };
+        let tokens = backend.tokens().unwrap_or_else(|| self.tokens());
+        Ok(Some(User {
+            id: self.user_id.clone(),
+            token: tokens.token,
+            refresh_token: tokens.refresh_token,
+            username: self.username.clone(),
+            password: String::new(),
+            collection,
+        }))
+    }
+
+    async fn fetch_collection(&self, backend: &BackendClient) -> rubook_lib::Result<Vec<Book>> {
+        if self.is_expired() {
+            backend.refresh().await?;
+        }
+        backend.get_books(&self.user_id).await
+    }
+}
+
+pub fn session_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(SESSION_FILE))
+}
+
+pub fn load() -> Option<Session> {
+    let contents = fs::read_to_string(session_path()?).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+pub fn save(session: &Session) -> io::Result<()> {
+    let path = session_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory found"))?;
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&path)?;
+    // NOTE:(akotro) The mode above only applies when the file is created
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+
+    let contents = serde_json::to_string_pretty(session)?;
+    file.write_all(contents.as_bytes())
+}
+
+/// Wipes the stored session, returns whether there was one to wipe.
+pub fn clear() -> io::Result<bool> {
+    let path = match session_path() {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Restores the stored session, wiping it when the backend no longer accepts it. It's kept when
+/// the backend can't be reached, so it can be restored once it's back.
+pub async fn restore_user(backend: &BackendClient) -> Option<User> {
+    let session = load()?;
+    match session.restore(backend).await {
+        Ok(Some(user)) => Some(user),
+        Ok(None) => {
+            clear().ok();
+            None
+        }
+        Err(e) => {
+            eprintln!("Couldn't restore the session: {}", e);
+            None
+        }
+    }
+}
+
+/// Stores the tokens of a refresh in the session, see `BackendClient::on_tokens_refreshed`.
+pub fn update_tokens(tokens: &AuthTokens) {
+    let mut session = match load() {
+        Some(session) => session,
+        None => return,
+    };
+    session.token = tokens.token.clone();
+    session.refresh_token = tokens.refresh_token.clone();
+    if let Err(e) = save(&session) {
+        eprintln!("Couldn't save session: {}", e);
+    }
+}
+
+/// Ends the stored session on the backend and wipes it, returns whether there was one.
+pub async fn logout(backend: &BackendClient) -> io::Result<bool> {
+    if let Some(session) = load().filter(|session| session.backend_url == backend.base_url()) {
+        backend.set_tokens(Some(session.tokens()));
+        if let Err(e) = backend.logout().await {
+            eprintln!("Couldn't end the session on the backend: {}", e);
+        }
+    }
+    clear()
+}
+
+pub fn remember(backend: &BackendClient, user: &User) {
+    if let Err(e) = save(&Session::new(backend, user)) {
+        eprintln!("Couldn't save session: {}", e);
+    }
+}
+
+fn create_private_dir(dir: &std::path::Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+ALTER TABLE mirrors ADD COLUMN resolver VARCHAR(32);
+
+UPDATE mirrors SET resolver = 'library.lol'
+WHERE host_url IN ('http://library.lol/', 'https://libgen.me/');
+
+UPDATE mirrors SET resolver = 'ads'
+WHERE host_url IN ('http://libgen.lc/', 'https://libgen.rocks/');
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE volume_infos DROP COLUMN cover_url;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE volume_infos ADD COLUMN cover_url TEXT;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_username_normalized ON users;
+
+ALTER TABLE users DROP COLUMN username_normalized;
@@ -0,0 +1,46 @@
+-- Your SQL goes here
+
+-- Usernames that only differ in case or surrounding spaces are duplicates. Renaming them here
+-- would lock their owners out without telling anyone, so the migration stops and lists them
+-- until they are renamed or removed by hand. So does a username with characters outside
+-- printable ASCII, the backend only normalizes those the way LOWER and TRIM do
+DROP PROCEDURE IF EXISTS check_unique_usernames;
+
+CREATE PROCEDURE check_unique_usernames()
+BEGIN
+    DECLARE conflicts TEXT;
+
+    SELECT GROUP_CONCAT(username ORDER BY username SEPARATOR ', ') INTO conflicts
+    FROM users
+    WHERE username NOT REGEXP '^[ -~]*$';
+
+    IF conflicts IS NOT NULL THEN
+        SET conflicts = LEFT(CONCAT('Usernames must be ASCII, rename them first: ', conflicts), 128);
+        SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = conflicts;
+    END IF;
+
+    SELECT GROUP_CONCAT(duplicates.usernames SEPARATOR '; ') INTO conflicts
+    FROM (
+        SELECT GROUP_CONCAT(username ORDER BY username SEPARATOR ', ') AS usernames
+        FROM users
+        GROUP BY LOWER(TRIM(username))
+        HAVING COUNT(*) > 1
+    ) AS duplicates;
+
+    IF conflicts IS NOT NULL THEN
+        SET conflicts = LEFT(CONCAT('Duplicate usernames, rename them first: ', conflicts), 128);
+        SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = conflicts;
+    END IF;
+END;
+
+CALL check_unique_usernames();
+
+DROP PROCEDURE check_unique_usernames;
+
+ALTER TABLE users ADD COLUMN username_normalized VARCHAR(255) NOT NULL DEFAULT '';
+
+UPDATE users SET username_normalized = LOWER(TRIM(username));
+
+ALTER TABLE users ALTER COLUMN username_normalized DROP DEFAULT;
+
+CREATE UNIQUE INDEX users_username_normalized ON users (username_normalized);
@@ -0,0 +1,15 @@
+-- This file should undo anything in `up.sql`
+
+-- IPv6 addresses don't fit anymore, they are cut to 15 characters instead of being dropped so
+-- the blacklist keeps its rows. A cut address that collides with another row is a duplicate
+DELETE longer
+FROM ip_blacklist AS longer
+JOIN ip_blacklist AS other
+    ON LEFT(other.ip_address, 15) = LEFT(longer.ip_address, 15)
+    AND (LENGTH(other.ip_address) <= 15 OR other.id < longer.id)
+WHERE LENGTH(longer.ip_address) > 15;
+
+UPDATE ip_blacklist SET ip_address = LEFT(ip_address, 15) WHERE LENGTH(ip_address) > 15;
+ALTER TABLE ip_blacklist MODIFY ip_address VARCHAR(15) NOT NULL;
+
+DROP TABLE login_attempts;
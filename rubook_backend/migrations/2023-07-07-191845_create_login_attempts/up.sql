@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE login_attempts (
+    id INTEGER PRIMARY KEY AUTO_INCREMENT,
+    username_normalized VARCHAR(255) NOT NULL,
+    ip_address VARCHAR(45) NOT NULL,
+    outcome VARCHAR(16) NOT NULL,
+    attempted_at BIGINT NOT NULL,
+    INDEX login_attempts_username (username_normalized, attempted_at),
+    INDEX login_attempts_ip_address (ip_address, attempted_at)
+);
+
+-- Room for IPv6 addresses, which can be blacklisted automatically now
+ALTER TABLE ip_blacklist MODIFY ip_address VARCHAR(45) NOT NULL;
@@ -1,17 +1,210 @@
 use std::{
+    env,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
     sync::{Arc, Mutex},
     time::{SystemTime, UNIX_EPOCH, Duration},
 };
 
-use actix_web::{web, HttpRequest, HttpResponse, rt::time::sleep};
+use actix_web::{
+    dev::Payload, error::InternalError, rt::time::sleep, web, FromRequest, HttpRequest,
+    HttpResponse,
+};
 use argon2::{
-    password_hash::{rand_core::OsRng, SaltString},
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        SaltString,
+    },
     Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
 };
+use diesel::{MysqlConnection, QueryResult};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use rubook_lib::user::UserClaims;
+use rubook_lib::{models::ApiResponse, user::UserClaims};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db_models::{FailedLogins, NewLoginAttempt},
+    db_util,
+};
+
+pub const ADMIN_ROLE: &str = "admin";
+// NOTE:(akotro) Clients refresh their access token, so it can be short lived
+const ACCESS_TOKEN_MINUTES: i64 = 15;
+const REFRESH_TOKEN_DAYS: i64 = 30;
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 32;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+// NOTE:(akotro) Hashing is slow on purpose, don't let a request make it arbitrarily slower
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+// NOTE:(akotro) Failed logins count for an hour. Past the free ones, every failure doubles the
+// wait before the next attempt is checked, from 30 seconds up to an hour.
+const LOGIN_WINDOW_SECS: i64 = 60 * 60;
+const FREE_FAILURES_PER_USERNAME: i64 = 5;
+const FREE_FAILURES_PER_IP: i64 = 20;
+const BASE_LOCKOUT_SECS: i64 = 30;
+const MAX_LOCKOUT_SECS: i64 = 60 * 60;
+const LOGIN_ATTEMPT_RETENTION_DAYS: i64 = 90;
+const AUTO_BLACKLIST_FAILURES: &str = "AUTO_BLACKLIST_FAILURES";
+const TRUSTED_PROXIES: &str = "TRUSTED_PROXIES";
+
+/// The user a request's bearer token was issued to. Extracting it rejects requests without a
+/// valid, unexpired token of an active session with 401 Unauthorized.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: String,
+    pub role: String,
+    /// The session the token belongs to, its `jti`
+    pub session_id: String,
+}
+
+impl AuthenticatedUser {
+    pub fn is_admin(&self) -> bool {
+        self.role == ADMIN_ROLE
+    }
+
+    /// Only the user themselves or an admin may access `/users/{user_id}/...`.
+    pub fn authorize(&self, user_id: &str) -> Result<(), HttpResponse> {
+        if self.id == user_id || self.is_admin() {
+            Ok(())
+        } else {
+            Err(forbidden())
+        }
+    }
+
+    pub fn require_admin(&self) -> Result<(), HttpResponse> {
+        if self.is_admin() {
+            Ok(())
+        } else {
+            Err(forbidden())
+        }
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let identity = validate_token(req);
+        let pool = req
+            .app_data::<web::Data<db_util::MySqlPool>>()
+            .expect("Missing app data: database pool")
+            .clone();
+
+        Box::pin(async move {
+            let unauthorized =
+                |response| InternalError::from_response("Unauthorized", response).into();
+            let identity = identity.map_err(unauthorized)?;
+
+            let session_id = identity.session_id.clone();
+            let is_active = web::block(move || {
+                let mut conn = db_util::get_connection(&pool);
+                db_util::is_session_active(&mut conn, &session_id, now())
+            })
+            .await;
+            match is_active {
+                Ok(Ok(true)) => Ok(identity),
+                _ => Err(unauthorized(HttpResponse::Unauthorized().finish())),
+            }
+        })
+    }
+}
+
+/// How logins are throttled, beyond the fixed backoff.
+#[derive(Debug, Clone, Default)]
+pub struct LoginPolicy {
+    /// Failed logins from one ip within the window that get it blacklisted, `None` never does
+    pub auto_blacklist_failures: Option<i64>,
+}
+
+impl LoginPolicy {
+    /// Reads `AUTO_BLACKLIST_FAILURES`, auto blacklisting is off when it's unset or 0.
+    pub fn from_env() -> LoginPolicy {
+        LoginPolicy {
+            auto_blacklist_failures: env::var(AUTO_BLACKLIST_FAILURES)
+                .ok()
+                .and_then(|failures| failures.trim().parse::<i64>().ok())
+                .filter(|failures| *failures > 0),
+        }
+    }
+}
+
+/// Reverse proxies allowed to name the client of a request with `X-Forwarded-For`. Requests
+/// from anywhere else are identified by their peer address, whatever headers they carry.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+impl TrustedProxies {
+    /// Reads the comma separated ips in `TRUSTED_PROXIES`, none are trusted when it's unset.
+    pub fn from_env() -> TrustedProxies {
+        let proxies = env::var(TRUSTED_PROXIES).unwrap_or_default();
+        TrustedProxies(
+            proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| {
+                    proxy
+                        .parse()
+                        .unwrap_or_else(|_| panic!("Invalid ip in {TRUSTED_PROXIES}: {proxy}"))
+                })
+                .collect(),
+        )
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(ip)
+    }
+}
+
+/// Body of `/auth/refresh`.
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// A session's refresh token. Only its hash is stored, the token itself is sent to the client.
+pub struct RefreshToken {
+    pub token: String,
+    pub hash: String,
+    pub expires_at: i64,
+}
+
+impl RefreshToken {
+    pub fn generate() -> RefreshToken {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+
+        RefreshToken {
+            hash: hash_refresh_token(&token),
+            token,
+            expires_at: now() + chrono::Duration::days(REFRESH_TOKEN_DAYS).num_seconds(),
+        }
+    }
+}
+
+// NOTE:(akotro) Refresh tokens are random, so a plain sha256 is enough to store them
+pub fn hash_refresh_token(refresh_token: &str) -> String {
+    hex::encode(Sha256::digest(refresh_token.as_bytes()))
+}
+
+/// Seconds since the unix epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64
+}
 
-use crate::db_util;
+fn forbidden() -> HttpResponse {
+    HttpResponse::Forbidden().json(ApiResponse::<()>::error(
+        "Not allowed to access this resource".to_string(),
+    ))
+}
 
 pub fn generate_password_hash(password: String) -> Result<String, argon2::password_hash::Error> {
     let argon2 = Argon2::default();
@@ -23,6 +216,49 @@ pub fn generate_password_hash(password: String) -> Result<String, argon2::passwo
     Ok(password_hash)
 }
 
+fn bad_request(message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(ApiResponse::<()>::error(message))
+}
+
+/// Usernames are 3 to 32 ascii letters, digits, `_`, `-` and `.`, so ones that look the same
+/// are the same once lowercased.
+pub fn validate_username(username: &str) -> Result<(), HttpResponse> {
+    let length = username.chars().count();
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+        return Err(bad_request(format!(
+            "Username must be {} to {} characters long",
+            MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+        )));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(bad_request(
+            "Username may only contain letters, digits, '_', '-' and '.'".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn validate_new_password(password: &str, username: &str) -> Result<(), HttpResponse> {
+    let length = password.chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        return Err(bad_request(format!(
+            "Password must be {} to {} characters long",
+            MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+        )));
+    }
+    if password.trim().eq_ignore_ascii_case(username.trim()) {
+        return Err(bad_request(
+            "Password can't be the same as the username".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
 pub fn validate_password(stored_hash: &str, password: &str) -> bool {
     let parsed_hash = match PasswordHash::new(stored_hash) {
         Ok(hash) => hash,
@@ -36,10 +272,18 @@ pub fn validate_password(stored_hash: &str, password: &str) -> bool {
     }
 }
 
-pub fn generate_token(req: &HttpRequest, username: String) -> String {
+pub fn generate_token(
+    req: &HttpRequest,
+    user_id: String,
+    role: String,
+    session_id: String,
+) -> String {
     let claims = UserClaims {
-        sub: username,
-        exp: (chrono::Utc::now() + chrono::Duration::hours(24)).timestamp() as usize,
+        sub: user_id,
+        exp: (chrono::Utc::now() + chrono::Duration::minutes(ACCESS_TOKEN_MINUTES)).timestamp()
+            as usize,
+        role,
+        jti: session_id,
     };
 
     let secret_key = req
@@ -55,7 +299,7 @@ pub fn generate_token(req: &HttpRequest, username: String) -> String {
     .unwrap()
 }
 
-pub fn validate_token(req: &HttpRequest) -> Result<(), HttpResponse> {
+fn validate_token(req: &HttpRequest) -> Result<AuthenticatedUser, HttpResponse> {
     let token = req
         .headers()
         .get("Authorization")
@@ -71,20 +315,21 @@ pub fn validate_token(req: &HttpRequest) -> Result<(), HttpResponse> {
     let validation = Validation::default();
     let user_claims = decode::<UserClaims>(
         token,
-        &DecodingKey::from_secret(secret_key.as_bytes().as_ref()),
+        &DecodingKey::from_secret(secret_key.as_bytes()),
         &validation,
     )
     .map_err(|_| HttpResponse::Unauthorized().finish())?;
 
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs() as usize;
-    if user_claims.claims.exp < now {
+    // NOTE:(akotro) Tokens from before sessions have no jti
+    if user_claims.claims.exp < now() as usize || user_claims.claims.jti.is_empty() {
         return Err(HttpResponse::Unauthorized().finish());
     }
 
-    Ok(())
+    Ok(AuthenticatedUser {
+        id: user_claims.claims.sub,
+        role: user_claims.claims.role,
+        session_id: user_claims.claims.jti,
+    })
 }
 
 pub async fn get_ip_blacklist(pool: db_util::MySqlPool) -> Result<Vec<String>, diesel::result::Error> {
@@ -117,16 +362,53 @@ pub async fn update_blacklist(db_pool: db_util::MySqlPool, blacklist: IpBlacklis
         let updated_blacklist = get_ip_blacklist(db_pool.clone()).await.unwrap_or_default();
         *blacklist.lock().unwrap() = updated_blacklist;
 
+        let pool = db_pool.clone();
+        let pruned = web::block(move || {
+            let mut conn = db_util::get_connection(&pool);
+            let before = now() - chrono::Duration::days(LOGIN_ATTEMPT_RETENTION_DAYS).num_seconds();
+            db_util::delete_login_attempts_before(&mut conn, before)
+        })
+        .await;
+        if let Ok(Err(error)) = pruned {
+            println!("Couldn't prune login attempts: {error}");
+        }
+
         // NOTE:(akotro) Update ip blacklist every 60 minutes
         sleep(Duration::from_secs(3600)).await;
     }
 }
 
+/// The ip a request is throttled and blacklisted by. That's the peer address, unless the peer
+/// is a trusted proxy, then it's the last hop in `X-Forwarded-For` that isn't one.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let peer = req.peer_addr()?.ip();
+    let trusted_proxies = match req.app_data::<web::Data<TrustedProxies>>() {
+        Some(trusted_proxies) if trusted_proxies.contains(&peer) => trusted_proxies,
+        _ => return Some(peer.to_string()),
+    };
+
+    // NOTE:(akotro) Each proxy appends the address it got the request from, so only the hops
+    // on the right were added by proxies we trust. Anything left of them is up to the client.
+    let mut client = peer;
+    let forwarded_for = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    for hop in forwarded_for.rsplit(',').map(str::trim) {
+        match hop.parse::<IpAddr>() {
+            Ok(hop) if trusted_proxies.contains(&hop) => client = hop,
+            Ok(hop) => return Some(hop.to_string()),
+            Err(_) => break,
+        }
+    }
+
+    Some(client.to_string())
+}
+
 pub fn validate_ip(req: &HttpRequest) -> Result<(), HttpResponse> {
-    let connection_info = req.connection_info();
-    let ip = connection_info
-        .realip_remote_addr()
-        .ok_or_else(|| HttpResponse::Unauthorized().finish())?;
+    let ip = client_ip(req).ok_or_else(|| HttpResponse::Unauthorized().finish())?;
 
     let ip_blacklist = req
         .app_data::<web::Data<IpBlacklist>>()
@@ -134,10 +416,175 @@ pub fn validate_ip(req: &HttpRequest) -> Result<(), HttpResponse> {
         .as_ref();
     let ip_blacklist = ip_blacklist.lock().unwrap();
 
-    if ip_blacklist.contains(&ip.to_string()) {
+    if ip_blacklist.contains(&ip) {
         println!("Blocked ip: {ip}");
         return Err(HttpResponse::Unauthorized().finish());
     }
 
     Ok(())
 }
+
+/// Seconds until the next login attempt is checked, given the failures so far.
+fn lockout_remaining(failed_logins: &FailedLogins, free_failures: i64, now: i64) -> i64 {
+    if failed_logins.count < free_failures {
+        return 0;
+    }
+
+    let doublings = (failed_logins.count - free_failures).min(16) as u32;
+    let lockout = (BASE_LOCKOUT_SECS << doublings).min(MAX_LOCKOUT_SECS);
+    failed_logins
+        .last_attempted_at
+        .map(|last_attempted_at| last_attempted_at + lockout - now)
+        .unwrap_or(0)
+        .max(0)
+}
+
+/// Checks whether a login may be attempted, recording it as locked out when it may not.
+/// Returns the seconds to wait, 0 when the password can be checked.
+pub fn check_login_lockout(
+    conn: &mut MysqlConnection,
+    username_normalized: &str,
+    ip: &str,
+) -> QueryResult<i64> {
+    let now = now();
+    let since = now - LOGIN_WINDOW_SECS;
+    let by_username = db_util::get_failed_logins_by_username(conn, username_normalized, since)?;
+    let by_ip = db_util::get_failed_logins_by_ip(conn, ip, since)?;
+
+    let retry_after = lockout_remaining(&by_username, FREE_FAILURES_PER_USERNAME, now)
+        .max(lockout_remaining(&by_ip, FREE_FAILURES_PER_IP, now));
+    if retry_after > 0 {
+        println!("Locked out login of '{username_normalized}' from {ip} for {retry_after}s");
+        record_login_attempt(conn, username_normalized, ip, db_util::LOGIN_LOCKED_OUT)?;
+    }
+
+    Ok(retry_after)
+}
+
+pub fn record_login_attempt(
+    conn: &mut MysqlConnection,
+    username_normalized: &str,
+    ip: &str,
+    outcome: &str,
+) -> QueryResult<usize> {
+    db_util::create_login_attempt(
+        conn,
+        &NewLoginAttempt {
+            username_normalized,
+            ip_address: ip,
+            outcome,
+            attempted_at: now(),
+        },
+    )
+}
+
+/// Records a failed login, blacklisting the ip when the policy says so. Returns whether it
+/// was blacklisted.
+pub fn record_failed_login(
+    conn: &mut MysqlConnection,
+    policy: &LoginPolicy,
+    username_normalized: &str,
+    ip: &str,
+) -> QueryResult<bool> {
+    record_login_attempt(conn, username_normalized, ip, db_util::LOGIN_FAILED)?;
+
+    let threshold = match policy.auto_blacklist_failures {
+        Some(threshold) => threshold,
+        None => return Ok(false),
+    };
+    let by_ip = db_util::get_failed_logins_by_ip(conn, ip, now() - LOGIN_WINDOW_SECS)?;
+    if by_ip.count < threshold {
+        return Ok(false);
+    }
+
+    db_util::blacklist_ip(conn, ip)?;
+    println!("Blacklisted ip after {} failed logins: {ip}", by_ip.count);
+    Ok(true)
+}
+
+/// Adds an ip to the in-memory blacklist right away, instead of at the next hourly update.
+pub fn block_ip(req: &HttpRequest, ip: String) {
+    let ip_blacklist = req
+        .app_data::<web::Data<IpBlacklist>>()
+        .expect("Missing app data: ip blacklist");
+    let mut ip_blacklist = ip_blacklist.lock().unwrap();
+    if !ip_blacklist.contains(&ip) {
+        ip_blacklist.push(ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    const ATTACKER: &str = "203.0.113.7";
+    const VICTIM: &str = "198.51.100.1";
+    const PROXY: &str = "10.0.0.1";
+
+    fn request(peer: &str, forwarded_for: &str, trusted_proxies: &[&str]) -> HttpRequest {
+        let trusted_proxies = trusted_proxies
+            .iter()
+            .map(|proxy| proxy.parse().unwrap())
+            .collect();
+        TestRequest::default()
+            .peer_addr(SocketAddr::new(peer.parse().unwrap(), 443))
+            .insert_header(("X-Forwarded-For", forwarded_for))
+            .app_data(web::Data::new(TrustedProxies(trusted_proxies)))
+            .app_data(web::Data::new(IpBlacklist::default()))
+            .to_http_request()
+    }
+
+    #[test]
+    fn spoofed_forwarded_for_is_ignored_without_trusted_proxies() {
+        for forwarded_for in [VICTIM, "192.0.2.1", "not an ip", "192.0.2.1, 192.0.2.2"] {
+            let req = request(ATTACKER, forwarded_for, &[]);
+            assert_eq!(client_ip(&req).as_deref(), Some(ATTACKER));
+        }
+    }
+
+    #[test]
+    fn spoofed_forwarded_for_is_ignored_from_untrusted_peers() {
+        let req = request(ATTACKER, VICTIM, &[PROXY]);
+        assert_eq!(client_ip(&req).as_deref(), Some(ATTACKER));
+    }
+
+    #[test]
+    fn trusted_proxy_names_the_client_it_saw() {
+        let req = request(PROXY, ATTACKER, &[PROXY]);
+        assert_eq!(client_ip(&req).as_deref(), Some(ATTACKER));
+
+        // NOTE:(akotro) The client sent the first hop, the proxy appended the second
+        let req = request(PROXY, &format!("{VICTIM}, {ATTACKER}"), &[PROXY]);
+        assert_eq!(client_ip(&req).as_deref(), Some(ATTACKER));
+    }
+
+    #[test]
+    fn chained_trusted_proxies_are_skipped() {
+        let forwarded_for = format!("{VICTIM}, {ATTACKER}, 10.0.0.2");
+        let req = request(PROXY, &forwarded_for, &[PROXY, "10.0.0.2"]);
+        assert_eq!(client_ip(&req).as_deref(), Some(ATTACKER));
+
+        let req = request(PROXY, "garbage, 10.0.0.2", &[PROXY, "10.0.0.2"]);
+        assert_eq!(client_ip(&req).as_deref(), Some("10.0.0.2"));
+    }
+
+    #[test]
+    fn spoofed_forwarded_for_does_not_blacklist_a_third_party() {
+        let req = request(ATTACKER, VICTIM, &[]);
+        block_ip(&req, client_ip(&req).unwrap());
+
+        let ip_blacklist = req.app_data::<web::Data<IpBlacklist>>().unwrap();
+        assert_eq!(*ip_blacklist.lock().unwrap(), vec![ATTACKER.to_string()]);
+        assert!(validate_ip(&req).is_err());
+
+        let victim_req = TestRequest::default()
+            .peer_addr(SocketAddr::new(VICTIM.parse().unwrap(), 443))
+            .app_data(ip_blacklist.clone())
+            .to_http_request();
+        assert!(validate_ip(&victim_req).is_ok());
+    }
+}
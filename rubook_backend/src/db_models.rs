@@ -7,15 +7,31 @@ use crate::schema::*;
 pub struct DbUser {
     pub id: String,
     pub username: String,
+    /// The argon2 hash, never sent to clients
+    #[serde(skip_serializing)]
     pub password: String,
+    pub role: String,
+    /// The trimmed, lowercase username, unique so names can't differ only in case
+    pub username_normalized: String,
 }
 
-#[derive(AsChangeset, Insertable, Serialize, Deserialize)]
+#[derive(AsChangeset, Insertable, Serialize, Deserialize, Clone)]
 #[diesel(table_name = users)]
 pub struct NewUser {
     pub id: String,
     pub username: String,
     pub password: String,
+    #[serde(default, skip_serializing)]
+    pub username_normalized: String,
+}
+
+/// Changes to an account, `None` fields are left as they are.
+#[derive(AsChangeset)]
+#[diesel(table_name = users)]
+pub struct UserChanges<'a> {
+    pub username: Option<&'a str>,
+    pub username_normalized: Option<&'a str>,
+    pub password: Option<&'a str>,
 }
 
 #[derive(Queryable, Serialize, Deserialize, Debug)]
@@ -37,6 +53,7 @@ pub struct DbVolumeInfo {
     pub publisher: Option<String>,
     pub published_date: Option<String>,
     pub description: Option<String>,
+    pub cover_url: Option<String>,
 }
 
 #[derive(AsChangeset, Insertable, Serialize, Deserialize)]
@@ -48,6 +65,7 @@ pub struct NewVolumeInfo<'a> {
     pub publisher: Option<&'a str>,
     pub published_date: Option<&'a str>,
     pub description: Option<&'a str>,
+    pub cover_url: Option<&'a str>,
 }
 
 #[derive(Queryable, Serialize, Deserialize, Debug)]
@@ -106,6 +124,7 @@ pub struct DbMirror {
     pub download_pattern: Option<String>,
     pub sync_url: Option<String>,
     pub cover_pattern: Option<String>,
+    pub resolver: Option<String>,
 }
 
 #[derive(AsChangeset, Insertable, Serialize, Deserialize)]
@@ -119,6 +138,7 @@ pub struct NewMirror<'a> {
     pub download_pattern: Option<&'a str>,
     pub sync_url: Option<&'a str>,
     pub cover_pattern: Option<&'a str>,
+    pub resolver: Option<&'a str>,
 }
 
 #[derive(Queryable, Serialize, Deserialize, Debug)]
@@ -132,3 +152,71 @@ pub struct DbIp {
 pub struct NewIp<'a> {
     pub ip_address: &'a str,
 }
+
+#[derive(Queryable, Serialize, Deserialize, Debug)]
+pub struct DbSession {
+    pub id: String,
+    pub user_id: String,
+    pub refresh_token_hash: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub revoked: bool,
+}
+
+#[derive(Insertable, Serialize, Deserialize)]
+#[diesel(table_name = sessions)]
+pub struct NewSession<'a> {
+    pub id: &'a str,
+    pub user_id: &'a str,
+    pub refresh_token_hash: &'a str,
+    pub created_at: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Insertable, Serialize, Deserialize)]
+#[diesel(table_name = login_attempts)]
+pub struct NewLoginAttempt<'a> {
+    pub username_normalized: &'a str,
+    pub ip_address: &'a str,
+    pub outcome: &'a str,
+    pub attempted_at: i64,
+}
+
+/// The failed logins of a username or ip in a time window.
+#[derive(Queryable, Debug, Default)]
+pub struct FailedLogins {
+    pub count: i64,
+    pub last_attempted_at: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use rubook_lib::user::User;
+
+    use super::*;
+
+    #[test]
+    fn password_hashes_are_not_serialized() {
+        let db_user = DbUser {
+            id: String::from("id"),
+            username: String::from("Alice"),
+            password: String::from("$argon2id$hash"),
+            role: String::from("user"),
+            username_normalized: String::from("alice"),
+        };
+        let json = serde_json::to_value(&db_user).unwrap();
+        assert_eq!(json.get("password"), None);
+        assert_eq!(json["username"], "Alice");
+
+        let user = User {
+            password: db_user.password,
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&user).unwrap();
+        assert_eq!(json.get("password"), None);
+        assert_eq!(
+            serde_json::from_value::<User>(json).unwrap(),
+            User::default()
+        );
+    }
+}
@@ -6,7 +6,9 @@ use diesel_migrations::MigrationHarness;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations};
 use r2d2::Pool;
 use rubook_lib::libgen::mirrors::Mirror;
-use rubook_lib::models::{AccessInfo, Book, BookFormat, IndustryIdentifier, Ip, VolumeInfo};
+use rubook_lib::models::{
+    AccessInfo, Book, BookFormat, ImageLinks, IndustryIdentifier, Ip, VolumeInfo,
+};
 use rubook_lib::user::User;
 use std::{env, fmt};
 
@@ -66,13 +68,31 @@ pub fn get_users(conn: &mut MysqlConnection) -> QueryResult<Vec<DbUser>> {
     users::table.load::<DbUser>(conn)
 }
 
-pub fn create_user(conn: &mut MysqlConnection, new_user: &NewUser) -> QueryResult<DbUser> {
-    let existing_user = users::table
-        .filter(users::username.eq(&new_user.username))
-        .first::<DbUser>(conn)
-        .optional()?;
+/// The form usernames are compared in, `users.username_normalized` holds it. Usernames are
+/// printable ASCII, where this is what the migration's `LOWER(TRIM(username))` does.
+pub fn normalize_username(username: &str) -> String {
+    username.trim_matches(' ').to_ascii_lowercase()
+}
 
-    if let Some(_) = existing_user {
+fn is_username_taken(
+    conn: &mut MysqlConnection,
+    username: &str,
+    except_user_id: Option<&str>,
+) -> QueryResult<bool> {
+    let mut query = users::table
+        .filter(users::username_normalized.eq(normalize_username(username)))
+        .into_boxed();
+    if let Some(user_id) = except_user_id {
+        query = query.filter(users::id.ne(user_id));
+    }
+
+    Ok(query.first::<DbUser>(conn).optional()?.is_some())
+}
+
+/// Creates a user, failing with `UniqueViolation` when the username is taken. The unique
+/// index catches concurrent registrations of the same name.
+pub fn create_user(conn: &mut MysqlConnection, new_user: &NewUser) -> QueryResult<DbUser> {
+    if is_username_taken(conn, &new_user.username, None)? {
         return Err(Error::DatabaseError(
             DatabaseErrorKind::UniqueViolation,
             Box::new(format!(
@@ -83,12 +103,13 @@ pub fn create_user(conn: &mut MysqlConnection, new_user: &NewUser) -> QueryResul
     }
 
     diesel::insert_into(users::table)
-        .values(new_user)
+        .values(NewUser {
+            username_normalized: normalize_username(&new_user.username),
+            ..new_user.clone()
+        })
         .execute(conn)?;
 
-    users::table
-        .filter(users::username.eq(&new_user.username))
-        .first::<DbUser>(conn)
+    users::table.find(&new_user.id).first::<DbUser>(conn)
 }
 
 pub fn get_user_by_id(conn: &mut MysqlConnection, user_id: &str) -> QueryResult<User> {
@@ -99,6 +120,7 @@ pub fn get_user_by_id(conn: &mut MysqlConnection, user_id: &str) -> QueryResult<
     Ok(User {
         id: db_user.id,
         token: String::new(),
+        refresh_token: String::new(),
         username: db_user.username,
         password: db_user.password,
         collection,
@@ -107,7 +129,7 @@ pub fn get_user_by_id(conn: &mut MysqlConnection, user_id: &str) -> QueryResult<
 
 pub fn get_user_by_credentials(conn: &mut MysqlConnection, username: &str) -> QueryResult<User> {
     let db_user = users::table
-        .filter(users::username.eq(username))
+        .filter(users::username_normalized.eq(normalize_username(username)))
         .first::<DbUser>(conn)?;
 
     let collection = get_books_by_user_id(conn, &db_user.id)?;
@@ -115,21 +137,42 @@ pub fn get_user_by_credentials(conn: &mut MysqlConnection, username: &str) -> Qu
     Ok(User {
         id: db_user.id,
         token: String::new(),
+        refresh_token: String::new(),
         username: db_user.username,
         password: db_user.password,
         collection,
     })
 }
 
-pub fn update_user(conn: &mut MysqlConnection, user_id: &str, user: &User) -> QueryResult<usize> {
-    let updated_user = NewUser {
-        id: user.id.clone(),
-        username: user.username.clone(),
-        password: user.password.clone(),
-    };
+pub fn get_user_role(conn: &mut MysqlConnection, user_id: &str) -> QueryResult<String> {
+    users::table
+        .find(user_id)
+        .select(users::role)
+        .first::<String>(conn)
+}
+
+/// Updates the username and password hash of a user. The password has to be hashed already,
+/// the normalised username is filled in.
+pub fn update_user(
+    conn: &mut MysqlConnection,
+    user_id: &str,
+    changes: &UserChanges,
+) -> QueryResult<usize> {
+    if let Some(username) = changes.username {
+        if is_username_taken(conn, username, Some(user_id))? {
+            return Err(Error::DatabaseError(
+                DatabaseErrorKind::UniqueViolation,
+                Box::new(format!("User already exists with username: {}", username)),
+            ));
+        }
+    }
 
+    let username_normalized = changes.username.map(normalize_username);
     diesel::update(users::table.find(user_id))
-        .set(&updated_user)
+        .set(&UserChanges {
+            username_normalized: username_normalized.as_deref(),
+            ..*changes
+        })
         .execute(conn)
 }
 
@@ -137,6 +180,85 @@ pub fn delete_user(conn: &mut MysqlConnection, user_id: &str) -> QueryResult<usi
     diesel::delete(users::table.find(user_id)).execute(conn)
 }
 
+// NOTE:(akotro) Sessions
+
+/// Starts a session, dropping the user's sessions that can't be used anymore.
+pub fn create_session(conn: &mut MysqlConnection, new_session: &NewSession) -> QueryResult<usize> {
+    diesel::delete(
+        sessions::table
+            .filter(sessions::user_id.eq(new_session.user_id))
+            .filter(
+                sessions::revoked
+                    .eq(true)
+                    .or(sessions::expires_at.le(new_session.created_at)),
+            ),
+    )
+    .execute(conn)?;
+
+    diesel::insert_into(sessions::table)
+        .values(new_session)
+        .execute(conn)
+}
+
+pub fn get_session_by_refresh_token_hash(
+    conn: &mut MysqlConnection,
+    refresh_token_hash: &str,
+) -> QueryResult<Option<DbSession>> {
+    sessions::table
+        .filter(sessions::refresh_token_hash.eq(refresh_token_hash))
+        .first::<DbSession>(conn)
+        .optional()
+}
+
+/// Replaces the refresh token of a session. Only one of two refreshes with the same token
+/// updates a row, the other one gets 0.
+pub fn rotate_session(
+    conn: &mut MysqlConnection,
+    session_id: &str,
+    old_refresh_token_hash: &str,
+    new_refresh_token_hash: &str,
+    expires_at: i64,
+) -> QueryResult<usize> {
+    diesel::update(
+        sessions::table
+            .find(session_id)
+            .filter(sessions::refresh_token_hash.eq(old_refresh_token_hash))
+            .filter(sessions::revoked.eq(false)),
+    )
+    .set((
+        sessions::refresh_token_hash.eq(new_refresh_token_hash),
+        sessions::expires_at.eq(expires_at),
+    ))
+    .execute(conn)
+}
+
+pub fn is_session_active(
+    conn: &mut MysqlConnection,
+    session_id: &str,
+    now: i64,
+) -> QueryResult<bool> {
+    let session = sessions::table
+        .find(session_id)
+        .filter(sessions::revoked.eq(false))
+        .filter(sessions::expires_at.gt(now))
+        .first::<DbSession>(conn)
+        .optional()?;
+
+    Ok(session.is_some())
+}
+
+pub fn revoke_session(conn: &mut MysqlConnection, session_id: &str) -> QueryResult<usize> {
+    diesel::update(sessions::table.find(session_id))
+        .set(sessions::revoked.eq(true))
+        .execute(conn)
+}
+
+pub fn revoke_sessions_by_user_id(conn: &mut MysqlConnection, user_id: &str) -> QueryResult<usize> {
+    diesel::update(sessions::table.filter(sessions::user_id.eq(user_id)))
+        .set(sessions::revoked.eq(true))
+        .execute(conn)
+}
+
 // NOTE:(akotro) Books
 
 pub fn create_book(conn: &mut MysqlConnection, book: &Book, user_id: &str) -> QueryResult<usize> {
@@ -162,7 +284,7 @@ pub fn create_book(conn: &mut MysqlConnection, book: &Book, user_id: &str) -> Qu
             create_authors(
                 transaction_context,
                 &book.id,
-                &book
+                book
                     .volume_info
                     .authors
                     .as_ref()
@@ -172,7 +294,7 @@ pub fn create_book(conn: &mut MysqlConnection, book: &Book, user_id: &str) -> Qu
             create_industry_identifiers(
                 transaction_context,
                 &book.id,
-                &book
+                book
                     .volume_info
                     .industry_identifiers
                     .as_ref()
@@ -253,6 +375,7 @@ pub fn create_volume_info(
         publisher: volume_info.publisher.as_deref(),
         published_date: volume_info.published_date.as_deref(),
         description: volume_info.description.as_deref(),
+        cover_url: volume_info.cover_url(),
     };
 
     diesel::insert_into(volume_infos::table)
@@ -292,6 +415,10 @@ pub fn get_volume_info_by_book_id(
         description: db_volume_info.description,
         authors: Some(authors),
         industry_identifiers: Some(industry_identifiers),
+        image_links: db_volume_info.cover_url.map(|cover_url| ImageLinks {
+            thumbnail: Some(cover_url),
+            ..Default::default()
+        }),
     })
 }
 
@@ -307,6 +434,7 @@ pub fn update_volume_info(
         publisher: volume_info.publisher.as_deref(),
         published_date: volume_info.published_date.as_deref(),
         description: volume_info.description.as_deref(),
+        cover_url: volume_info.cover_url(),
     };
 
     diesel::update(volume_infos::table.filter(volume_infos::book_id.eq(book_id)))
@@ -379,7 +507,7 @@ pub fn delete_access_info(conn: &mut MysqlConnection, book_id: &str) -> QueryRes
 pub fn create_authors(
     conn: &mut MysqlConnection,
     book_id: &str,
-    authors: &Vec<String>,
+    authors: &[String],
 ) -> QueryResult<usize> {
     let new_authors: Vec<NewAuthor> = authors
         .iter()
@@ -417,7 +545,7 @@ fn delete_authors(conn: &mut MysqlConnection, book: &Book) -> QueryResult<usize>
 pub fn create_industry_identifiers(
     conn: &mut MysqlConnection,
     book_id: &str,
-    industry_identifiers: &Vec<IndustryIdentifier>,
+    industry_identifiers: &[IndustryIdentifier],
 ) -> QueryResult<usize> {
     let new_industry_identifiers: Vec<NewIndustryIdentifier> = industry_identifiers
         .iter()
@@ -453,7 +581,7 @@ pub fn get_industry_identifiers_by_book_id(
 pub fn update_industry_identifiers(
     conn: &mut MysqlConnection,
     book_id: &str,
-    industry_identifiers: &Vec<IndustryIdentifier>,
+    industry_identifiers: &[IndustryIdentifier],
 ) -> QueryResult<usize> {
     let updated_industry_identifiers: Vec<NewIndustryIdentifier> = industry_identifiers
         .iter()
@@ -496,14 +624,15 @@ pub fn create_mirrors(conn: &mut MysqlConnection, mirrors: &[Mirror]) -> QueryRe
     let new_mirrors: Vec<NewMirror> = mirrors
         .iter()
         .map(|mirror| NewMirror {
-            host_url: &mirror.host_url.as_str(),
-            search_url: mirror.search_url.as_ref().map(|url| url.as_str()),
-            search_url_fiction: mirror.search_url_fiction.as_ref().map(|url| url.as_str()),
-            download_url: mirror.download_url.as_ref().map(|url| url.as_str()),
-            download_url_fiction: mirror.download_url_fiction.as_ref().map(|url| url.as_str()),
+            host_url: mirror.host_url.as_str(),
+            search_url: mirror.search_url.as_deref(),
+            search_url_fiction: mirror.search_url_fiction.as_deref(),
+            download_url: mirror.download_url.as_deref(),
+            download_url_fiction: mirror.download_url_fiction.as_deref(),
             download_pattern: mirror.download_pattern.as_deref(),
-            sync_url: mirror.sync_url.as_ref().map(|url| url.as_str()),
+            sync_url: mirror.sync_url.as_deref(),
             cover_pattern: mirror.cover_pattern.as_deref(),
+            resolver: mirror.resolver.as_deref(),
         })
         .collect();
 
@@ -526,6 +655,7 @@ pub fn get_mirrors(conn: &mut MysqlConnection) -> QueryResult<Vec<Mirror>> {
             download_pattern: db_mirror.download_pattern.clone(),
             sync_url: db_mirror.sync_url.clone(),
             cover_pattern: db_mirror.cover_pattern.clone(),
+            resolver: db_mirror.resolver.clone(),
         })
         .collect())
 }
@@ -536,14 +666,15 @@ pub fn get_mirror_by_id(conn: &mut MysqlConnection, mirror_id: i32) -> QueryResu
 
 pub fn update_mirror(conn: &mut MysqlConnection, id: i32, mirror: &Mirror) -> QueryResult<usize> {
     let new_mirror: NewMirror = NewMirror {
-        host_url: &mirror.host_url.as_str(),
-        search_url: mirror.search_url.as_ref().map(|url| url.as_str()),
-        search_url_fiction: mirror.search_url_fiction.as_ref().map(|url| url.as_str()),
-        download_url: mirror.download_url.as_ref().map(|url| url.as_str()),
-        download_url_fiction: mirror.download_url_fiction.as_ref().map(|url| url.as_str()),
+        host_url: mirror.host_url.as_str(),
+        search_url: mirror.search_url.as_deref(),
+        search_url_fiction: mirror.search_url_fiction.as_deref(),
+        download_url: mirror.download_url.as_deref(),
+        download_url_fiction: mirror.download_url_fiction.as_deref(),
         download_pattern: mirror.download_pattern.as_deref(),
-        sync_url: mirror.sync_url.as_ref().map(|url| url.as_str()),
+        sync_url: mirror.sync_url.as_deref(),
         cover_pattern: mirror.cover_pattern.as_deref(),
+        resolver: mirror.resolver.as_deref(),
     };
 
     diesel::update(mirrors::table.find(id))
@@ -584,3 +715,90 @@ pub fn get_ip_blacklist(conn: &mut MysqlConnection) -> QueryResult<Vec<Ip>> {
 pub fn delete_ip(conn: &mut MysqlConnection, ip: &str) -> QueryResult<usize> {
     diesel::delete(ip_blacklist::table.filter(ip_blacklist::ip_address.eq(ip))).execute(conn)
 }
+
+pub fn blacklist_ip(conn: &mut MysqlConnection, ip: &str) -> QueryResult<usize> {
+    diesel::insert_or_ignore_into(ip_blacklist::table)
+        .values(NewIp { ip_address: ip })
+        .execute(conn)
+}
+
+// NOTE:(akotro) Login attempts
+
+pub const LOGIN_SUCCEEDED: &str = "succeeded";
+pub const LOGIN_FAILED: &str = "failed";
+/// Rejected without checking the password, because of earlier failures
+pub const LOGIN_LOCKED_OUT: &str = "locked_out";
+
+pub fn create_login_attempt(
+    conn: &mut MysqlConnection,
+    login_attempt: &NewLoginAttempt,
+) -> QueryResult<usize> {
+    diesel::insert_into(login_attempts::table)
+        .values(login_attempt)
+        .execute(conn)
+}
+
+/// Failed logins of a username since `since` that came after its last successful login.
+pub fn get_failed_logins_by_username(
+    conn: &mut MysqlConnection,
+    username_normalized: &str,
+    since: i64,
+) -> QueryResult<FailedLogins> {
+    let last_success = login_attempts::table
+        .filter(login_attempts::username_normalized.eq(username_normalized))
+        .filter(login_attempts::outcome.eq(LOGIN_SUCCEEDED))
+        .select(diesel::dsl::max(login_attempts::attempted_at))
+        .first::<Option<i64>>(conn)?;
+
+    login_attempts::table
+        .filter(login_attempts::username_normalized.eq(username_normalized))
+        .filter(login_attempts::outcome.eq(LOGIN_FAILED))
+        .filter(login_attempts::attempted_at.ge(since.max(last_success.unwrap_or(since))))
+        .select((
+            diesel::dsl::count_star(),
+            diesel::dsl::max(login_attempts::attempted_at),
+        ))
+        .first::<FailedLogins>(conn)
+}
+
+/// Failed logins from an ip since `since`. Successful ones don't reset them, an attacker can
+/// have an account of their own.
+pub fn get_failed_logins_by_ip(
+    conn: &mut MysqlConnection,
+    ip: &str,
+    since: i64,
+) -> QueryResult<FailedLogins> {
+    login_attempts::table
+        .filter(login_attempts::ip_address.eq(ip))
+        .filter(login_attempts::outcome.eq(LOGIN_FAILED))
+        .filter(login_attempts::attempted_at.ge(since))
+        .select((
+            diesel::dsl::count_star(),
+            diesel::dsl::max(login_attempts::attempted_at),
+        ))
+        .first::<FailedLogins>(conn)
+}
+
+pub fn delete_login_attempts_before(conn: &mut MysqlConnection, before: i64) -> QueryResult<usize> {
+    diesel::delete(login_attempts::table.filter(login_attempts::attempted_at.lt(before)))
+        .execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_username_matches_mysql_on_ascii() {
+        // NOTE:(akotro) What `LOWER(TRIM(username))` gives for these, TRIM only removes spaces
+        assert_eq!(normalize_username("  Alice "), "alice");
+        assert_eq!(normalize_username("Bob_The-Builder.42"), "bob_the-builder.42");
+        assert_eq!(normalize_username("\tCarol"), "\tcarol");
+    }
+
+    #[test]
+    fn normalize_username_leaves_non_ascii_alone() {
+        // NOTE:(akotro) Such names can't be registered and stop the migration
+        assert_eq!(normalize_username("ÉLODIE"), "Élodie");
+    }
+}
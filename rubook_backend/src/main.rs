@@ -54,6 +54,13 @@ async fn main() -> std::io::Result<()> {
     dotenv().ok();
     let secret_key = Data::new(env::var(JWT_SECRET).expect("JWT_SECRET must be set"));
 
+    let login_policy = Data::new(auth::LoginPolicy::from_env());
+    if let Some(failures) = login_policy.auto_blacklist_failures {
+        println!("Blacklisting ips after {failures} failed logins within an hour");
+    }
+
+    let trusted_proxies = Data::new(auth::TrustedProxies::from_env());
+
     let db_pool = db_util::init_database();
 
     let ssl_builder = configure_ssl();
@@ -78,7 +85,11 @@ async fn main() -> std::io::Result<()> {
                     .app_data(Data::new(db_pool.clone()))
                     .app_data(Data::new(ip_blacklist.clone()))
                     .app_data(secret_key.clone())
+                    .app_data(login_policy.clone())
+                    .app_data(trusted_proxies.clone())
                     .service(get_users_route)
+                    .service(get_user_by_id_route)
+                    .service(update_user_route)
                     .service(delete_user_route)
                     .service(get_books_by_user_id_route)
                     .service(create_book_route)
@@ -88,9 +99,11 @@ async fn main() -> std::io::Result<()> {
                     .service(
                         web::scope("auth")
                             .service(register_user_route)
-                            .service(login_user_route),
+                            .service(login_user_route)
+                            .service(refresh_route)
+                            .service(logout_route),
                     )
-                    .default_service(web::route().to(|| HttpResponse::NotFound())),
+                    .default_service(web::route().to(HttpResponse::NotFound)),
             )
     })
     .bind_openssl("0.0.0.0:9595", ssl_builder)?
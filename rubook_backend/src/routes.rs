@@ -1,22 +1,52 @@
-use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use actix_web::{delete, get, http::header, post, put, web, HttpRequest, HttpResponse};
+use diesel::{
+    result::{DatabaseErrorKind, Error as DieselError},
+    Connection,
+};
 use rubook_lib::{
     models::{ApiResponse, Book},
-    user::User,
+    user::{AccountUpdate, AuthTokens, User},
 };
 use uuid::Uuid;
 
 use crate::{
     auth::{
-        generate_password_hash, generate_token, validate_ip, validate_password, validate_token,
+        block_ip, check_login_lockout, client_ip, generate_password_hash, generate_token,
+        hash_refresh_token, now, record_failed_login, record_login_attempt, validate_ip,
+        validate_new_password, validate_password, validate_username, AuthenticatedUser,
+        LoginPolicy, RefreshRequest, RefreshToken,
     },
-    db_models::NewUser,
+    db_models::{NewSession, NewUser, UserChanges},
     db_util::{
-        create_book, create_user, delete_book, delete_user, get_book_by_id, get_books_by_user_id,
-        get_connection, get_mirrors, get_user_by_credentials, get_user_by_id, get_users,
-        update_user, MySqlPool,
+        create_book, create_session, create_user, delete_book, delete_user, get_book_by_id,
+        get_books_by_user_id, get_connection, get_mirrors, get_session_by_refresh_token_hash,
+        get_user_by_credentials, get_user_by_id, get_user_role, get_users, normalize_username,
+        revoke_session, revoke_sessions_by_user_id, rotate_session, update_user, MySqlPool,
+        LOGIN_SUCCEEDED,
     },
 };
 
+fn is_unique_violation(error: &DieselError) -> bool {
+    matches!(
+        error,
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)
+    )
+}
+
+fn username_taken(username: &str) -> HttpResponse {
+    HttpResponse::Conflict().json(ApiResponse::<()>::error(format!(
+        "Username '{}' is already taken",
+        username
+    )))
+}
+
+fn not_found(what: &str, id: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(ApiResponse::<()>::error(format!(
+        "{} '{}' not found",
+        what, id
+    )))
+}
+
 #[post("/register")]
 async fn register_user_route(
     pool: web::Data<MySqlPool>,
@@ -28,6 +58,13 @@ async fn register_user_route(
     }
 
     new_user.0.id = Uuid::new_v4().to_string();
+    new_user.0.username = new_user.0.username.trim().to_string();
+    if let Err(err) = validate_username(&new_user.0.username) {
+        return err;
+    }
+    if let Err(err) = validate_new_password(&new_user.0.password, &new_user.0.username) {
+        return err;
+    }
     let username = new_user.0.username.clone();
 
     let hashed_password = match generate_password_hash(new_user.0.password.clone()) {
@@ -39,24 +76,50 @@ async fn register_user_route(
     };
     new_user.0.password = hashed_password;
 
-    let result = web::block(move || {
-        let mut conn = get_connection(&pool);
-        create_user(&mut conn, &new_user.0)
+    let session_id = Uuid::new_v4().to_string();
+    let refresh_token = RefreshToken::generate();
+
+    let result = web::block({
+        let session_id = session_id.clone();
+        let refresh_token_hash = refresh_token.hash.clone();
+        let expires_at = refresh_token.expires_at;
+        move || {
+            let mut conn = get_connection(&pool);
+            // NOTE:(akotro) Otherwise a failed session leaves the username taken by a registration
+            // the client was told failed
+            conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                let db_user = create_user(conn, &new_user.0)?;
+                create_session(
+                    conn,
+                    &NewSession {
+                        id: &session_id,
+                        user_id: &db_user.id,
+                        refresh_token_hash: &refresh_token_hash,
+                        created_at: now(),
+                        expires_at,
+                    },
+                )?;
+                Ok(db_user)
+            })
+        }
     })
     .await;
 
     match result {
         Ok(users_result) => match users_result {
             Ok(db_user) => {
-                let token = generate_token(&req, username);
+                let token =
+                    generate_token(&req, db_user.id.clone(), db_user.role.clone(), session_id);
                 HttpResponse::Created().json(ApiResponse::success(User {
                     id: db_user.id,
                     username: db_user.username,
-                    password: db_user.password,
-                    token: token.clone(),
+                    password: String::new(),
+                    token,
+                    refresh_token: refresh_token.token,
                     collection: Vec::new(),
                 }))
             }
+            Err(error) if is_unique_violation(&error) => username_taken(&username),
             Err(error) => HttpResponse::InternalServerError()
                 .json(ApiResponse::<()>::error(error.to_string())),
         },
@@ -66,9 +129,63 @@ async fn register_user_route(
     }
 }
 
+/// What the database knows about a login, before the password is checked.
+enum LoginLookup {
+    LockedOut { retry_after: i64 },
+    Found(User, String),
+    UnknownUsername,
+}
+
+fn too_many_logins(retry_after: i64) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+        .json(ApiResponse::<()>::error(format!(
+            "Too many failed logins, try again in {} seconds",
+            retry_after
+        )))
+}
+
+/// Records a failed login and answers it, blacklisting the ip when the policy says so.
+async fn failed_login(
+    pool: web::Data<MySqlPool>,
+    policy: web::Data<LoginPolicy>,
+    req: &HttpRequest,
+    username_normalized: String,
+    ip: String,
+) -> HttpResponse {
+    record_wrong_password(pool, policy, req, username_normalized, ip).await;
+    HttpResponse::Unauthorized().json(ApiResponse::<()>::error("Invalid credentials".to_string()))
+}
+
+/// Records a wrong password like a failed login, blacklisting the ip when the policy says so.
+async fn record_wrong_password(
+    pool: web::Data<MySqlPool>,
+    policy: web::Data<LoginPolicy>,
+    req: &HttpRequest,
+    username_normalized: String,
+    ip: String,
+) {
+    let result = web::block({
+        let ip = ip.clone();
+        move || {
+            let mut conn = get_connection(&pool);
+            record_failed_login(&mut conn, &policy, &username_normalized, &ip)
+        }
+    })
+    .await;
+
+    match result {
+        Ok(Ok(true)) => block_ip(req, ip),
+        Ok(Ok(false)) => {}
+        Ok(Err(error)) => println!("Couldn't record failed login: {error}"),
+        Err(error) => println!("Couldn't record failed login: {error}"),
+    }
+}
+
 #[post("/login")]
 async fn login_user_route(
     pool: web::Data<MySqlPool>,
+    policy: web::Data<LoginPolicy>,
     req: HttpRequest,
     credentials: web::Json<NewUser>,
 ) -> HttpResponse {
@@ -76,28 +193,178 @@ async fn login_user_route(
         return err;
     }
 
-    let username = credentials.0.username.clone();
+    let ip = client_ip(&req).unwrap_or_default();
+    let username_normalized = normalize_username(&credentials.0.username);
     let password = credentials.0.password.clone();
 
+    let result = web::block({
+        let pool = pool.clone();
+        let username_normalized = username_normalized.clone();
+        let ip = ip.clone();
+        move || {
+            let mut conn = get_connection(&pool);
+            let retry_after = check_login_lockout(&mut conn, &username_normalized, &ip)?;
+            if retry_after > 0 {
+                return Ok(LoginLookup::LockedOut { retry_after });
+            }
+
+            match get_user_by_credentials(&mut conn, &username_normalized) {
+                Ok(user) => {
+                    let role = get_user_role(&mut conn, &user.id)?;
+                    Ok(LoginLookup::Found(user, role))
+                }
+                Err(DieselError::NotFound) => Ok(LoginLookup::UnknownUsername),
+                Err(error) => Err(error),
+            }
+        }
+    })
+    .await;
+
+    let (mut user, role) = match result {
+        Ok(lookup_result) => match lookup_result {
+            Ok(LoginLookup::Found(user, role)) => (user, role),
+            Ok(LoginLookup::LockedOut { retry_after }) => return too_many_logins(retry_after),
+            Ok(LoginLookup::UnknownUsername) => {
+                // NOTE:(akotro) Hash anyway, so unknown usernames take as long as wrong passwords
+                let _ = generate_password_hash(password);
+                return failed_login(pool, policy, &req, username_normalized, ip).await;
+            }
+            Err(error) => {
+                return HttpResponse::InternalServerError()
+                    .json(ApiResponse::<()>::error(error.to_string()))
+            }
+        },
+        Err(error) => {
+            return HttpResponse::InternalServerError()
+                .json(ApiResponse::<()>::error(error.to_string()))
+        }
+    };
+
+    if !validate_password(&user.password, &password) {
+        return failed_login(pool, policy, &req, username_normalized, ip).await;
+    }
+
+    let session_id = Uuid::new_v4().to_string();
+    let refresh_token = RefreshToken::generate();
+
+    let session_result = web::block({
+        let session_id = session_id.clone();
+        let user_id = user.id.clone();
+        let refresh_token_hash = refresh_token.hash.clone();
+        let expires_at = refresh_token.expires_at;
+        move || {
+            let mut conn = get_connection(&pool);
+            record_login_attempt(&mut conn, &username_normalized, &ip, LOGIN_SUCCEEDED)?;
+            create_session(
+                &mut conn,
+                &NewSession {
+                    id: &session_id,
+                    user_id: &user_id,
+                    refresh_token_hash: &refresh_token_hash,
+                    created_at: now(),
+                    expires_at,
+                },
+            )
+        }
+    })
+    .await;
+
+    match session_result {
+        Ok(sessions_result) => match sessions_result {
+            Ok(_) => {
+                user.token = generate_token(&req, user.id.clone(), role, session_id);
+                user.refresh_token = refresh_token.token;
+                HttpResponse::Found().json(ApiResponse::success(user))
+            }
+            Err(error) => HttpResponse::InternalServerError()
+                .json(ApiResponse::<()>::error(error.to_string())),
+        },
+        Err(error) => {
+            HttpResponse::InternalServerError().json(ApiResponse::<()>::error(error.to_string()))
+        }
+    }
+}
+
+#[post("/refresh")]
+async fn refresh_route(
+    pool: web::Data<MySqlPool>,
+    req: HttpRequest,
+    refresh_request: web::Json<RefreshRequest>,
+) -> HttpResponse {
+    if let Err(err) = validate_ip(&req) {
+        return err;
+    }
+
+    let old_refresh_token_hash = hash_refresh_token(&refresh_request.0.refresh_token);
+    let refresh_token = RefreshToken::generate();
+
+    let result = web::block({
+        let new_refresh_token_hash = refresh_token.hash.clone();
+        let expires_at = refresh_token.expires_at;
+        move || {
+            let mut conn = get_connection(&pool);
+            let session =
+                match get_session_by_refresh_token_hash(&mut conn, &old_refresh_token_hash)? {
+                    Some(session) if !session.revoked && session.expires_at > now() => session,
+                    _ => return Ok(None),
+                };
+            // NOTE:(akotro) Each refresh token is used once, a concurrent refresh loses
+            let rows = rotate_session(
+                &mut conn,
+                &session.id,
+                &old_refresh_token_hash,
+                &new_refresh_token_hash,
+                expires_at,
+            )?;
+            if rows == 0 {
+                return Ok(None);
+            }
+            let role = get_user_role(&mut conn, &session.user_id)?;
+            Ok::<_, diesel::result::Error>(Some((session, role)))
+        }
+    })
+    .await;
+
+    match result {
+        Ok(sessions_result) => match sessions_result {
+            Ok(Some((session, role))) => {
+                let token = generate_token(&req, session.user_id, role, session.id);
+                HttpResponse::Ok().json(ApiResponse::success(AuthTokens {
+                    token,
+                    refresh_token: refresh_token.token,
+                }))
+            }
+            Ok(None) => HttpResponse::Unauthorized().json(ApiResponse::<()>::error(
+                "Invalid refresh token".to_string(),
+            )),
+            Err(error) => HttpResponse::InternalServerError()
+                .json(ApiResponse::<()>::error(error.to_string())),
+        },
+        Err(error) => {
+            HttpResponse::InternalServerError().json(ApiResponse::<()>::error(error.to_string()))
+        }
+    }
+}
+
+#[post("/logout")]
+async fn logout_route(
+    pool: web::Data<MySqlPool>,
+    req: HttpRequest,
+    identity: AuthenticatedUser,
+) -> HttpResponse {
+    if let Err(err) = validate_ip(&req) {
+        return err;
+    }
+
     let result = web::block(move || {
         let mut conn = get_connection(&pool);
-        get_user_by_credentials(&mut conn, &credentials.0.username.clone())
+        revoke_session(&mut conn, &identity.session_id)
     })
     .await;
 
     match result {
-        Ok(users_result) => match users_result {
-            Ok(mut user) => {
-                let is_valid_password = validate_password(&user.password, &password);
-                if is_valid_password {
-                    let token = generate_token(&req, username);
-                    user.token = token;
-                    HttpResponse::Found().json(ApiResponse::success(user))
-                } else {
-                    HttpResponse::Unauthorized()
-                        .json(ApiResponse::<()>::error("Invalid credentials".to_string()))
-                }
-            }
+        Ok(sessions_result) => match sessions_result {
+            Ok(rows) => HttpResponse::Ok().json(ApiResponse::success(rows)),
             Err(error) => HttpResponse::InternalServerError()
                 .json(ApiResponse::<()>::error(error.to_string())),
         },
@@ -108,12 +375,16 @@ async fn login_user_route(
 }
 
 #[get("/users")]
-async fn get_users_route(pool: web::Data<MySqlPool>, req: HttpRequest) -> HttpResponse {
+async fn get_users_route(
+    pool: web::Data<MySqlPool>,
+    req: HttpRequest,
+    identity: AuthenticatedUser,
+) -> HttpResponse {
     if let Err(err) = validate_ip(&req) {
         return err;
     }
 
-    if let Err(err) = validate_token(&req) {
+    if let Err(err) = identity.require_admin() {
         return err;
     }
 
@@ -138,24 +409,30 @@ async fn get_users_route(pool: web::Data<MySqlPool>, req: HttpRequest) -> HttpRe
 async fn get_user_by_id_route(
     pool: web::Data<MySqlPool>,
     req: HttpRequest,
+    identity: AuthenticatedUser,
     id: web::Path<String>,
 ) -> HttpResponse {
     if let Err(err) = validate_ip(&req) {
         return err;
     }
 
-    if let Err(err) = validate_token(&req) {
+    if let Err(err) = identity.authorize(&id) {
         return err;
     }
 
-    let result = web::block(move || {
-        let mut conn = get_connection(&pool);
-        get_user_by_id(&mut conn, &id)
+    let user_id = id.into_inner();
+    let result = web::block({
+        let user_id = user_id.clone();
+        move || {
+            let mut conn = get_connection(&pool);
+            get_user_by_id(&mut conn, &user_id)
+        }
     })
     .await;
     match result {
         Ok(users_result) => match users_result {
             Ok(user) => HttpResponse::Found().json(ApiResponse::success(user)),
+            Err(DieselError::NotFound) => not_found("User", &user_id),
             Err(error) => HttpResponse::InternalServerError()
                 .json(ApiResponse::<()>::error(error.to_string())),
         },
@@ -168,26 +445,157 @@ async fn get_user_by_id_route(
 #[put("/users/{id}")]
 async fn update_user_route(
     pool: web::Data<MySqlPool>,
+    policy: web::Data<LoginPolicy>,
     req: HttpRequest,
+    identity: AuthenticatedUser,
     id: web::Path<String>,
-    user: web::Json<User>,
+    update: web::Json<AccountUpdate>,
 ) -> HttpResponse {
     if let Err(err) = validate_ip(&req) {
         return err;
     }
 
-    if let Err(err) = validate_token(&req) {
+    if let Err(err) = identity.authorize(&id) {
         return err;
     }
 
-    let result = web::block(move || {
-        let mut conn = get_connection(&pool);
-        update_user(&mut conn, &id, &user.0)
+    let update = update.into_inner();
+    let username = update.username.as_deref().map(str::trim);
+    if username.is_none() && update.new_password.is_none() {
+        return HttpResponse::BadRequest()
+            .json(ApiResponse::<()>::error("Nothing to update".to_string()));
+    }
+    if let Some(Err(err)) = username.map(validate_username) {
+        return err;
+    }
+
+    let user_id = id.into_inner();
+    // NOTE:(akotro) Admins can change other users without knowing their password, anyone
+    // changing their own account is asked for it, so a stolen token alone can't take it over
+    let check_password = identity.id == user_id;
+    let ip = client_ip(&req).unwrap_or_default();
+
+    let user_result = web::block({
+        let pool = pool.clone();
+        let user_id = user_id.clone();
+        let ip = ip.clone();
+        move || {
+            let mut conn = get_connection(&pool);
+            let user = get_user_by_id(&mut conn, &user_id)?;
+            let username_normalized = normalize_username(&user.username);
+            let retry_after = if check_password {
+                check_login_lockout(&mut conn, &username_normalized, &ip)?
+            } else {
+                0
+            };
+            Ok::<_, DieselError>((user, username_normalized, retry_after))
+        }
+    })
+    .await;
+    let (mut user, username_normalized, retry_after) = match user_result {
+        Ok(Ok(user)) => user,
+        Ok(Err(DieselError::NotFound)) => return not_found("User", &user_id),
+        Ok(Err(error)) => {
+            return HttpResponse::InternalServerError()
+                .json(ApiResponse::<()>::error(error.to_string()))
+        }
+        Err(error) => {
+            return HttpResponse::InternalServerError()
+                .json(ApiResponse::<()>::error(error.to_string()))
+        }
+    };
+
+    if retry_after > 0 {
+        return too_many_logins(retry_after);
+    }
+    if check_password && !validate_password(&user.password, &update.current_password) {
+        record_wrong_password(pool, policy, &req, username_normalized, ip).await;
+        return HttpResponse::Forbidden().json(ApiResponse::<()>::error(
+            "Current password is incorrect".to_string(),
+        ));
+    }
+    if let Some(new_password) = &update.new_password {
+        if let Err(err) = validate_new_password(new_password, username.unwrap_or(&user.username)) {
+            return err;
+        }
+    }
+
+    let password_hash = match update.new_password {
+        Some(new_password) => match generate_password_hash(new_password) {
+            Ok(password_hash) => Some(password_hash),
+            Err(error) => {
+                return HttpResponse::InternalServerError()
+                    .json(ApiResponse::<()>::error(error.to_string()))
+            }
+        },
+        None => None,
+    };
+    let password_changed = password_hash.is_some();
+    // NOTE:(akotro) Only the user themselves gets a new session, not an admin changing them
+    let new_session = (password_changed && identity.id == user_id)
+        .then(|| (Uuid::new_v4().to_string(), RefreshToken::generate()));
+
+    let result = web::block({
+        let username = username.map(String::from);
+        let user_id = user_id.clone();
+        let new_session = new_session.as_ref().map(|(session_id, refresh_token)| {
+            (
+                session_id.clone(),
+                refresh_token.hash.clone(),
+                refresh_token.expires_at,
+            )
+        });
+        move || {
+            let mut conn = get_connection(&pool);
+            if check_password {
+                record_login_attempt(&mut conn, &username_normalized, &ip, LOGIN_SUCCEEDED)?;
+            }
+            conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                let rows = update_user(
+                    conn,
+                    &user_id,
+                    &UserChanges {
+                        username: username.as_deref(),
+                        username_normalized: None,
+                        password: password_hash.as_deref(),
+                    },
+                )?;
+                if password_changed {
+                    revoke_sessions_by_user_id(conn, &user_id)?;
+                }
+                if let Some((session_id, refresh_token_hash, expires_at)) = &new_session {
+                    create_session(
+                        conn,
+                        &NewSession {
+                            id: session_id,
+                            user_id: &user_id,
+                            refresh_token_hash,
+                            created_at: now(),
+                            expires_at: *expires_at,
+                        },
+                    )?;
+                }
+                Ok(rows)
+            })
+        }
     })
     .await;
+
     match result {
         Ok(users_result) => match users_result {
-            Ok(rows) => HttpResponse::Ok().json(ApiResponse::success(rows)),
+            Ok(_) => {
+                if let Some(username) = username {
+                    user.username = username.to_string();
+                }
+                if let Some((session_id, refresh_token)) = new_session {
+                    user.token = generate_token(&req, user_id, identity.role.clone(), session_id);
+                    user.refresh_token = refresh_token.token;
+                }
+                HttpResponse::Ok().json(ApiResponse::success(user))
+            }
+            Err(error) if is_unique_violation(&error) => {
+                username_taken(username.unwrap_or_default())
+            }
             Err(error) => HttpResponse::InternalServerError()
                 .json(ApiResponse::<()>::error(error.to_string())),
         },
@@ -201,13 +609,14 @@ async fn update_user_route(
 async fn delete_user_route(
     pool: web::Data<MySqlPool>,
     req: HttpRequest,
+    identity: AuthenticatedUser,
     id: web::Path<String>,
 ) -> HttpResponse {
     if let Err(err) = validate_ip(&req) {
         return err;
     }
 
-    if let Err(err) = validate_token(&req) {
+    if let Err(err) = identity.authorize(&id) {
         return err;
     }
 
@@ -232,6 +641,7 @@ async fn delete_user_route(
 async fn create_book_route(
     pool: web::Data<MySqlPool>,
     req: HttpRequest,
+    identity: AuthenticatedUser,
     user_id: web::Path<String>,
     book: web::Json<Book>,
 ) -> HttpResponse {
@@ -239,7 +649,7 @@ async fn create_book_route(
         return err;
     }
 
-    if let Err(err) = validate_token(&req) {
+    if let Err(err) = identity.authorize(&user_id) {
         return err;
     }
 
@@ -264,13 +674,14 @@ async fn create_book_route(
 async fn get_books_by_user_id_route(
     pool: web::Data<MySqlPool>,
     req: HttpRequest,
+    identity: AuthenticatedUser,
     user_id: web::Path<String>,
 ) -> HttpResponse {
     if let Err(err) = validate_ip(&req) {
         return err;
     }
 
-    if let Err(err) = validate_token(&req) {
+    if let Err(err) = identity.authorize(&user_id) {
         return err;
     }
 
@@ -295,24 +706,26 @@ async fn get_books_by_user_id_route(
 async fn get_book_by_id_route(
     pool: web::Data<MySqlPool>,
     req: HttpRequest,
+    _identity: AuthenticatedUser,
     id: web::Path<String>,
 ) -> HttpResponse {
     if let Err(err) = validate_ip(&req) {
         return err;
     }
 
-    if let Err(err) = validate_token(&req) {
-        return err;
-    }
-
-    let result = web::block(move || {
-        let mut conn = get_connection(&pool);
-        get_book_by_id(&mut conn, &id)
+    let book_id = id.into_inner();
+    let result = web::block({
+        let book_id = book_id.clone();
+        move || {
+            let mut conn = get_connection(&pool);
+            get_book_by_id(&mut conn, &book_id)
+        }
     })
     .await;
     match result {
         Ok(users_result) => match users_result {
             Ok(book) => HttpResponse::Ok().json(ApiResponse::success(book)),
+            Err(DieselError::NotFound) => not_found("Book", &book_id),
             Err(error) => HttpResponse::InternalServerError()
                 .json(ApiResponse::<()>::error(error.to_string())),
         },
@@ -326,18 +739,18 @@ async fn get_book_by_id_route(
 async fn delete_book_route(
     pool: web::Data<MySqlPool>,
     req: HttpRequest,
+    identity: AuthenticatedUser,
     params: web::Path<(String, String)>,
 ) -> HttpResponse {
     if let Err(err) = validate_ip(&req) {
         return err;
     }
 
-    if let Err(err) = validate_token(&req) {
+    let (user_id, book_id) = params.into_inner();
+    if let Err(err) = identity.authorize(&user_id) {
         return err;
     }
 
-    let (user_id, book_id) = params.into_inner();
-
     let result = web::block(move || {
         let mut conn = get_connection(&pool);
         delete_book(&mut conn, &user_id, &book_id)
@@ -356,15 +769,15 @@ async fn delete_book_route(
 }
 
 #[get("/mirrors")]
-async fn get_mirrors_route(pool: web::Data<MySqlPool>, req: HttpRequest) -> HttpResponse {
+async fn get_mirrors_route(
+    pool: web::Data<MySqlPool>,
+    req: HttpRequest,
+    _identity: AuthenticatedUser,
+) -> HttpResponse {
     if let Err(err) = validate_ip(&req) {
         return err;
     }
 
-    if let Err(err) = validate_token(&req) {
-        return err;
-    }
-
     let result = web::block(move || {
         let mut conn = get_connection(&pool);
         get_mirrors(&mut conn)
@@ -38,6 +38,16 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    login_attempts (id) {
+        id -> Integer,
+        username_normalized -> Varchar,
+        ip_address -> Varchar,
+        outcome -> Varchar,
+        attempted_at -> Bigint,
+    }
+}
+
 diesel::table! {
     mirrors (id) {
         id -> Integer,
@@ -49,6 +59,18 @@ diesel::table! {
         download_pattern -> Nullable<Text>,
         sync_url -> Nullable<Text>,
         cover_pattern -> Nullable<Text>,
+        resolver -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Char,
+        user_id -> Char,
+        refresh_token_hash -> Char,
+        created_at -> Bigint,
+        expires_at -> Bigint,
+        revoked -> Bool,
     }
 }
 
@@ -65,6 +87,8 @@ diesel::table! {
         id -> Char,
         username -> Text,
         password -> Text,
+        role -> Varchar,
+        username_normalized -> Varchar,
     }
 }
 
@@ -76,12 +100,14 @@ diesel::table! {
         publisher -> Nullable<Text>,
         published_date -> Nullable<Text>,
         description -> Nullable<Text>,
+        cover_url -> Nullable<Text>,
     }
 }
 
 diesel::joinable!(access_infos -> books (book_id));
 diesel::joinable!(authors -> books (book_id));
 diesel::joinable!(industry_identifiers -> books (book_id));
+diesel::joinable!(sessions -> users (user_id));
 diesel::joinable!(user_books -> books (book_id));
 diesel::joinable!(user_books -> users (user_id));
 diesel::joinable!(volume_infos -> books (book_id));
@@ -92,7 +118,9 @@ diesel::allow_tables_to_appear_in_same_query!(
     books,
     industry_identifiers,
     ip_blacklist,
+    login_attempts,
     mirrors,
+    sessions,
     user_books,
     users,
     volume_infos,
@@ -1,123 +1,420 @@
-use std::sync::Arc;
+use std::{
+    fmt, fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
-use reqwest::Client;
+use reqwest::{Certificate, Client, RequestBuilder, Response, StatusCode};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    ServerName,
+};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 
 use crate::{
+    error::Error,
     libgen::mirrors::Mirror,
     models::{ApiResponse, Book},
-    user::User,
+    user::{AccountUpdate, AuthTokens, User},
 };
 
-// lazy_static! {
-//     pub static ref BACKEND_URL: String = {
-//         dotenv().ok();
-//         env::var("BACKEND_URL").expect("BACKEND_URL must be set")
-//     };
-// }
-
-pub static BACKEND_URL: &str = "https://64.226.108.119:9595/rubook";
-// pub static BACKEND_URL: &str = "https://localhost:9595/rubook";
-
-pub async fn register_user(
-    client: &Arc<Client>,
-    username: String,
-    password: String,
-) -> Result<User, Box<dyn std::error::Error>> {
-    let new_user_json = json!(
-        {
-            "id": "",
-            "username": username,
-            "password": password
-        }
-    );
-    let response = client
-        .post(format!("{}/auth/register", BACKEND_URL))
-        .json(&new_user_json)
-        .send()
-        .await?;
-    let response_body = response.text().await?;
-    ApiResponse::<User>::from_response_body(&response_body)
+/// How the backend's TLS certificate is verified.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum TlsTrust {
+    /// Verify against the system root certificates
+    #[default]
+    System,
+    /// Verify against the system roots and this PEM encoded CA certificate
+    CaCertificate(PathBuf),
+    /// Accept only a certificate whose SHA-256 fingerprint matches, e.g. a self-signed one
+    Fingerprint(String),
+    /// Skip verification altogether
+    Insecure,
 }
 
-pub async fn login_user(
-    client: &Arc<Client>,
-    username: String,
-    password: String,
-) -> Result<User, Box<dyn std::error::Error>> {
-    let credentials_json = json!(
-        {
-            "id": "",
-            "username": username,
-            "password": password
+struct FingerprintVerifier {
+    fingerprint: Vec<u8>,
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(&end_entity.0).as_slice() == self.fingerprint.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "Certificate fingerprint doesn't match the pinned one".to_string(),
+            ))
         }
-    );
-    let response = client
-        .post(format!("{}/auth/login", BACKEND_URL))
-        .json(&credentials_json)
-        .send()
-        .await?;
-    let response_body = response.text().await?;
-    ApiResponse::<User>::from_response_body(&response_body)
+    }
 }
 
-pub async fn delete_user(
-    client: &Arc<Client>,
-    token: &str,
-    user_id: &str,
-) -> Result<usize, Box<dyn std::error::Error>> {
-    let response = client
-        .delete(format!("{}/users/{}", BACKEND_URL, user_id))
-        .bearer_auth(token)
-        .send()
-        .await?;
-    let response_body = response.text().await?;
-    ApiResponse::<usize>::from_response_body(&response_body)
+fn parse_fingerprint(fingerprint: &str) -> crate::Result<Vec<u8>> {
+    let fingerprint = fingerprint
+        .trim()
+        .trim_start_matches("sha256:")
+        .replace(':', "");
+    let fingerprint = hex::decode(fingerprint)
+        .map_err(|e| Error::Parse(format!("Invalid certificate fingerprint: {}", e)))?;
+    if fingerprint.len() != 32 {
+        return Err(Error::Parse(
+            "Certificate fingerprint must be a SHA-256 digest".to_string(),
+        ));
+    }
+    Ok(fingerprint)
 }
 
-pub async fn create_book(
-    client: &Arc<Client>,
-    token: &str,
-    book: &Book,
-    user_id: &str,
-) -> Result<usize, Box<dyn std::error::Error>> {
-    let response = client
-        .post(format!("{}/users/{}/books", BACKEND_URL, user_id))
-        .bearer_auth(token)
-        .json(&book)
-        .send()
-        .await?;
+/// Reads an `ApiResponse` from the backend, keeping the http status when it wasn't successful.
+async fn parse_response<T>(response: Response) -> crate::Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let status = response.status();
     let response_body = response.text().await?;
-    ApiResponse::<usize>::from_response_body(&response_body)
+    match serde_json::from_str::<ApiResponse<T>>(&response_body) {
+        Ok(api_response) => api_response.into_result(Some(status.as_u16())),
+        // NOTE:(akotro) Rejected tokens and blocked ips get an empty body
+        Err(_) if !status.is_success() => Err(Error::Api {
+            status: Some(status.as_u16()),
+            message: status
+                .canonical_reason()
+                .unwrap_or("Request failed")
+                .to_string(),
+        }),
+        Err(e) => Err(e.into()),
+    }
 }
 
-pub async fn delete_book(
-    client: &Arc<Client>,
-    token: &str,
-    user_id: &str,
-    book_id: String,
-) -> Result<usize, Box<dyn std::error::Error>> {
-    let response = client
-        .delete(format!(
-            "{}/users/{}/books/{}",
-            BACKEND_URL, user_id, book_id
-        ))
-        .bearer_auth(token)
-        .send()
-        .await?;
-    let response_body = response.text().await?;
-    ApiResponse::<usize>::from_response_body(&response_body)
+type RefreshHook = Arc<dyn Fn(&AuthTokens) + Send + Sync>;
+
+/// Client of the rubook backend. It keeps the tokens of the logged in user, shared between
+/// clones, and refreshes them when the backend rejects the access token.
+#[derive(Clone)]
+pub struct BackendClient {
+    client: Client,
+    base_url: String,
+    tokens: Arc<Mutex<Option<AuthTokens>>>,
+    // NOTE:(akotro) Refresh tokens are single use, so concurrent requests refresh one at a time
+    refresh_lock: Arc<tokio::sync::Mutex<()>>,
+    on_refresh: Option<RefreshHook>,
 }
 
-pub async fn get_mirrors(
-    client: &Arc<Client>,
-    token: &str,
-) -> Result<Vec<Mirror>, Box<dyn std::error::Error>> {
-    let response = client
-        .get(format!("{}/mirrors", BACKEND_URL))
-        .bearer_auth(token)
-        .send()
-        .await?;
-    let response_body = response.text().await?;
-    ApiResponse::<Vec<Mirror>>::from_response_body(&response_body)
+impl fmt::Debug for BackendClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BackendClient")
+            .field("base_url", &self.base_url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BackendClient {
+    pub fn new(
+        base_url: &str,
+        tls_trust: &TlsTrust,
+    ) -> crate::Result<BackendClient> {
+        let builder = Client::builder();
+        let builder = match tls_trust {
+            TlsTrust::System => builder,
+            TlsTrust::CaCertificate(path) => {
+                let certificate = Certificate::from_pem(&fs::read(path)?)?;
+                builder.add_root_certificate(certificate)
+            }
+            TlsTrust::Fingerprint(fingerprint) => {
+                let verifier = FingerprintVerifier {
+                    fingerprint: parse_fingerprint(fingerprint)?,
+                };
+                let tls_config = rustls::ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_custom_certificate_verifier(Arc::new(verifier))
+                    .with_no_client_auth();
+                builder.use_preconfigured_tls(tls_config)
+            }
+            TlsTrust::Insecure => builder.danger_accept_invalid_certs(true),
+        };
+
+        Ok(BackendClient::with_client(builder.build()?, base_url))
+    }
+
+    pub fn with_client(client: Client, base_url: &str) -> BackendClient {
+        BackendClient {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            tokens: Arc::new(Mutex::new(None)),
+            refresh_lock: Arc::new(tokio::sync::Mutex::new(())),
+            on_refresh: None,
+        }
+    }
+
+    /// Calls `hook` with the new tokens after every refresh or password change, e.g. to save
+    /// them.
+    pub fn on_tokens_refreshed(&mut self, hook: impl Fn(&AuthTokens) + Send + Sync + 'static) {
+        self.on_refresh = Some(Arc::new(hook));
+    }
+
+    pub fn tokens(&self) -> Option<AuthTokens> {
+        self.tokens.lock().unwrap().clone()
+    }
+
+    /// Uses these tokens for the requests that need a logged in user, `None` logs out locally.
+    pub fn set_tokens(&self, tokens: Option<AuthTokens>) {
+        *self.tokens.lock().unwrap() = tokens;
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Sends a request with the access token. When it's rejected, the tokens are refreshed and
+    /// the request is sent once more.
+    async fn send_authorized(
+        &self,
+        request: impl Fn(&Client) -> RequestBuilder,
+    ) -> crate::Result<Response> {
+        let token = self
+            .tokens()
+            .map(|tokens| tokens.token)
+            .ok_or_else(|| Error::Api {
+                status: Some(StatusCode::UNAUTHORIZED.as_u16()),
+                message: "Not logged in".to_string(),
+            })?;
+
+        let response = request(&self.client).bearer_auth(&token).send().await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        match self.refresh_rejected(&token).await {
+            Ok(tokens) => Ok(request(&self.client)
+                .bearer_auth(tokens.token)
+                .send()
+                .await?),
+            // NOTE:(akotro) The original 401 tells the caller to log in again
+            Err(_) => Ok(response),
+        }
+    }
+
+    /// Refreshes the tokens after `rejected_token` was rejected, unless another request
+    /// already did.
+    async fn refresh_rejected(&self, rejected_token: &str) -> crate::Result<AuthTokens> {
+        let _guard = self.refresh_lock.lock().await;
+        match self.tokens() {
+            Some(tokens) if tokens.token != rejected_token => Ok(tokens),
+            _ => self.refresh_tokens().await,
+        }
+    }
+
+    async fn refresh_tokens(&self) -> crate::Result<AuthTokens> {
+        let refresh_token = self
+            .tokens()
+            .map(|tokens| tokens.refresh_token)
+            .filter(|refresh_token| !refresh_token.is_empty())
+            .ok_or_else(|| Error::Api {
+                status: Some(StatusCode::UNAUTHORIZED.as_u16()),
+                message: "No refresh token".to_string(),
+            })?;
+
+        let response = self
+            .client
+            .post(self.url("/auth/refresh"))
+            .json(&json!({ "refresh_token": refresh_token }))
+            .send()
+            .await?;
+        let tokens: AuthTokens = parse_response(response).await?;
+
+        self.set_tokens(Some(tokens.clone()));
+        if let Some(on_refresh) = &self.on_refresh {
+            on_refresh(&tokens);
+        }
+        Ok(tokens)
+    }
+
+    /// Exchanges the refresh token for new tokens right away.
+    pub async fn refresh(&self) -> crate::Result<AuthTokens> {
+        let _guard = self.refresh_lock.lock().await;
+        self.refresh_tokens().await
+    }
+
+    /// Revokes the session on the backend and forgets the tokens.
+    pub async fn logout(&self) -> crate::Result<()> {
+        let result = match self.tokens() {
+            Some(_) => {
+                let response = self
+                    .send_authorized(|client| client.post(self.url("/auth/logout")))
+                    .await?;
+                // NOTE:(akotro) The backend answers with the number of revoked sessions
+                parse_response::<usize>(response).await.map(|_| ())
+            }
+            None => Ok(()),
+        };
+        self.set_tokens(None);
+        result
+    }
+
+    pub async fn register_user(
+        &self,
+        username: String,
+        password: String,
+    ) -> crate::Result<User> {
+        let new_user_json = json!(
+            {
+                "id": "",
+                "username": username,
+                "password": password
+            }
+        );
+        let response = self
+            .client
+            .post(self.url("/auth/register"))
+            .json(&new_user_json)
+            .send()
+            .await?;
+        let user: User = parse_response(response).await?;
+        self.set_tokens(Some(AuthTokens::from(&user)));
+        Ok(user)
+    }
+
+    pub async fn login_user(
+        &self,
+        username: String,
+        password: String,
+    ) -> crate::Result<User> {
+        let credentials_json = json!(
+            {
+                "id": "",
+                "username": username,
+                "password": password
+            }
+        );
+        let response = self
+            .client
+            .post(self.url("/auth/login"))
+            .json(&credentials_json)
+            .send()
+            .await?;
+        let user: User = parse_response(response).await?;
+        self.set_tokens(Some(AuthTokens::from(&user)));
+        Ok(user)
+    }
+
+    pub async fn get_user(&self, user_id: &str) -> crate::Result<User> {
+        let response = self
+            .send_authorized(|client| client.get(self.url(&format!("/users/{}", user_id))))
+            .await?;
+        parse_response(response).await
+    }
+
+    /// Changes the username or password of a user. A password change ends every session of
+    /// the user, the new tokens of this one are in the returned user.
+    pub async fn update_user(&self, user_id: &str, update: &AccountUpdate) -> crate::Result<User> {
+        let response = self
+            .send_authorized(|client| {
+                client
+                    .put(self.url(&format!("/users/{}", user_id)))
+                    .json(update)
+            })
+            .await?;
+        let user: User = parse_response(response).await?;
+
+        if !user.token.is_empty() {
+            let tokens = AuthTokens::from(&user);
+            self.set_tokens(Some(tokens.clone()));
+            if let Some(on_refresh) = &self.on_refresh {
+                on_refresh(&tokens);
+            }
+        }
+        Ok(user)
+    }
+
+    pub async fn delete_user(&self, user_id: &str) -> crate::Result<usize> {
+        let response = self
+            .send_authorized(|client| client.delete(self.url(&format!("/users/{}", user_id))))
+            .await?;
+        let deleted = parse_response(response).await?;
+        self.set_tokens(None);
+        Ok(deleted)
+    }
+
+    pub async fn get_books(&self, user_id: &str) -> crate::Result<Vec<Book>> {
+        let response = self
+            .send_authorized(|client| client.get(self.url(&format!("/users/{}/books", user_id))))
+            .await?;
+        parse_response(response).await
+    }
+
+    pub async fn create_book(&self, book: &Book, user_id: &str) -> crate::Result<usize> {
+        let response = self
+            .send_authorized(|client| {
+                client
+                    .post(self.url(&format!("/users/{}/books", user_id)))
+                    .json(&book)
+            })
+            .await?;
+        parse_response(response).await
+    }
+
+    pub async fn delete_book(&self, user_id: &str, book_id: String) -> crate::Result<usize> {
+        let response = self
+            .send_authorized(|client| {
+                client.delete(self.url(&format!("/users/{}/books/{}", user_id, book_id)))
+            })
+            .await?;
+        parse_response(response).await
+    }
+
+    pub async fn get_mirrors(&self) -> crate::Result<Vec<Mirror>> {
+        let response = self
+            .send_authorized(|client| client.get(self.url("/mirrors")))
+            .await?;
+        parse_response(response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::serve;
+
+    fn logged_in_client(address: &str) -> BackendClient {
+        let backend = BackendClient::new(address, &TlsTrust::System).unwrap();
+        backend.set_tokens(Some(AuthTokens {
+            token: String::from("access"),
+            refresh_token: String::from("refresh"),
+        }));
+        backend
+    }
+
+    #[tokio::test]
+    async fn logout_accepts_the_revoked_session_count() {
+        let (address, mut requested_paths) = serve("200 OK", r#"{"success":true,"message":"","data":1}"#).await;
+        let backend = logged_in_client(&address);
+
+        backend.logout().await.unwrap();
+
+        assert_eq!(requested_paths.recv().await.unwrap(), "/auth/logout");
+        assert!(backend.tokens().is_none());
+    }
+
+    #[tokio::test]
+    async fn logout_forgets_the_tokens_when_it_fails() {
+        let (address, _) = serve(
+            "500 Internal Server Error",
+            r#"{"success":false,"message":"Database error","data":null}"#,
+        ).await;
+        let backend = logged_in_client(&address);
+
+        assert!(backend.logout().await.is_err());
+        assert!(backend.tokens().is_none());
+    }
 }
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use reqwest::{header::CONTENT_TYPE, Client};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+const INDEX_FILE: &str = "index.json";
+const CONCURRENT_COVER_DOWNLOADS: usize = 4;
+
+/// Local cache of cover images.
+///
+/// Covers are stored under the sha256 of their content, so a cover that several books or
+/// mirrors link to is kept once. `index.json` maps each cover url to its file.
+pub struct CoverCache {
+    dir: PathBuf,
+    index: HashMap<String, String>,
+}
+
+enum Cover {
+    Cached(PathBuf),
+    Downloaded(Bytes, &'static str),
+}
+
+impl CoverCache {
+    pub fn open(dir: PathBuf) -> crate::Result<CoverCache> {
+        fs::create_dir_all(&dir)?;
+        let index = match fs::read_to_string(dir.join(INDEX_FILE)) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(CoverCache { dir, index })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The cached file of a cover url, if it has been downloaded before.
+    pub fn get(&self, url: &str) -> Option<PathBuf> {
+        self.index
+            .get(url)
+            .map(|file_name| self.dir.join(file_name))
+            .filter(|path| path.is_file())
+    }
+
+    pub async fn fetch(&mut self, client: &Client, url: &str) -> crate::Result<PathBuf> {
+        self.fetch_all(client, &[url]).await.remove(0)
+    }
+
+    /// The first of the cover urls that can be fetched, the failures are reported.
+    pub async fn fetch_first<'a>(
+        &mut self,
+        client: &Client,
+        urls: impl IntoIterator<Item = &'a str>,
+    ) -> Option<PathBuf> {
+        for url in urls {
+            match self.fetch(client, url).await {
+                Ok(path) => return Some(path),
+                Err(e) => eprintln!("Couldn't get the cover: {}", e),
+            }
+        }
+        None
+    }
+
+    /// Downloads the covers that aren't cached yet, a few at a time. The results are in the
+    /// order of `urls`.
+    pub async fn fetch_all(
+        &mut self,
+        client: &Client,
+        urls: &[&str],
+    ) -> Vec<crate::Result<PathBuf>> {
+        let covers = stream::iter(urls.iter().map(|url| {
+            let cached = self.get(url);
+            async move {
+                match cached {
+                    Some(path) => Ok(Cover::Cached(path)),
+                    None => download_cover(client, url)
+                        .await
+                        .map(|(bytes, extension)| Cover::Downloaded(bytes, extension)),
+                }
+            }
+        }))
+        .buffered(CONCURRENT_COVER_DOWNLOADS)
+        .collect::<Vec<_>>()
+        .await;
+
+        urls.iter()
+            .zip(covers)
+            .map(|(url, cover)| match cover? {
+                Cover::Cached(path) => Ok(path),
+                Cover::Downloaded(bytes, extension) => self.store(url, &bytes, extension),
+            })
+            .collect()
+    }
+
+    fn store(&mut self, url: &str, bytes: &[u8], extension: &str) -> crate::Result<PathBuf> {
+        let file_name = format!("{}.{}", hex::encode(Sha256::digest(bytes)), extension);
+        let path = self.dir.join(&file_name);
+        if !path.is_file() {
+            let part_path = path.with_extension(format!("{}.part", extension));
+            fs::write(&part_path, bytes)?;
+            fs::rename(&part_path, &path)?;
+        }
+
+        self.index.insert(url.to_string(), file_name);
+        fs::write(
+            self.dir.join(INDEX_FILE),
+            serde_json::to_string_pretty(&self.index)?,
+        )?;
+
+        Ok(path)
+    }
+}
+
+async fn download_cover(client: &Client, url: &str) -> crate::Result<(Bytes, &'static str)> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default();
+    // NOTE:(akotro) Mirrors answer missing covers with an html page instead of a 404
+    let extension = match content_type {
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        _ if content_type.starts_with("image/") => "jpg",
+        _ => return Err(Error::NotFound(format!("cover image at {}", url))),
+    };
+
+    Ok((response.bytes().await?, extension))
+}
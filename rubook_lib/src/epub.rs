@@ -0,0 +1,566 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{Read, Write},
+    path::Path,
+};
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{error::Error, models::VolumeInfo};
+
+const CONTAINER_PATH: &str = "META-INF/container.xml";
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+/// Manifest id and file name of an embedded cover
+const COVER_ID: &str = "rubook-cover";
+
+lazy_static! {
+    static ref ROOTFILE: Regex =
+        Regex::new(r#"<rootfile\b[^>]*\bfull-path\s*=\s*["']([^"']+)["']"#).unwrap();
+    static ref PACKAGE_UNIQUE_IDENTIFIER: Regex =
+        Regex::new(r#"<(?:\w+:)?package\b[^>]*\bunique-identifier\s*=\s*["']([^"']+)["']"#)
+            .unwrap();
+    static ref PACKAGE_VERSION_3: Regex =
+        Regex::new(r#"<(?:\w+:)?package\b[^>]*\bversion\s*=\s*["']3"#).unwrap();
+    static ref METADATA: Regex =
+        Regex::new(r"(?s)(<(?:\w+:)?metadata\b[^>]*>)(.*?)(</(?:\w+:)?metadata>)").unwrap();
+    static ref MANIFEST_END: Regex = Regex::new(r"</(?:\w+:)?manifest>").unwrap();
+    /// The elements that are rewritten, dublin core elements don't nest
+    static ref REPLACED_ELEMENT: Regex = Regex::new(
+        r"(?s)<dc:(?:title|creator|publisher|date|description|identifier)\b[^>]*?(?:/>|>.*?</dc:\w+>)\s*"
+    )
+    .unwrap();
+    /// EPUB 3 `<meta refines="#id">` of an element
+    static ref REFINES_META: Regex = Regex::new(
+        r#"(?s)<(?:\w+:)?meta\b[^>]*\brefines\s*=\s*["']#([^"']+)["'][^>]*?(?:/>|>.*?</(?:\w+:)?meta>)\s*"#
+    )
+    .unwrap();
+    /// EPUB 2 `<meta name="cover">`
+    static ref COVER_META: Regex = Regex::new(
+        r#"(?s)\s*<(?:\w+:)?meta\b[^>]*\bname\s*=\s*["']cover["'][^>]*?(?:/>|>.*?</(?:\w+:)?meta>)"#
+    )
+    .unwrap();
+    static ref COVER_ITEM: Regex =
+        Regex::new(r#"\s*<(?:\w+:)?item\b[^>]*\bid\s*=\s*["']rubook-cover["'][^>]*>"#).unwrap();
+    static ref COVER_PROPERTY_ONLY: Regex =
+        Regex::new(r#"\s+properties\s*=\s*["']\s*cover-image\s*["']"#).unwrap();
+    static ref COVER_PROPERTY: Regex =
+        Regex::new(r#"(\bproperties\s*=\s*["'][^"']*?)\s*\bcover-image\b"#).unwrap();
+    static ref ID_ATTRIBUTE: Regex = Regex::new(r#"\bid\s*=\s*["']([^"']+)["']"#).unwrap();
+}
+
+pub fn is_epub(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("epub"))
+}
+
+/// Rewrites the package metadata of an epub with the book's Google Books metadata: title,
+/// authors, publisher, date, ISBNs and description. The other metadata (language, subjects,
+/// the book's own identifier) is kept.
+///
+/// With `cover`, that image is embedded and marked as the cover.
+pub fn write_metadata(
+    path: &Path,
+    volume_info: &VolumeInfo,
+    cover: Option<&Path>,
+) -> crate::Result<()> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+
+    let container = read_entry(&mut archive, CONTAINER_PATH)?;
+    let opf_path = ROOTFILE
+        .captures(&container)
+        .map(|captures| captures[1].to_string())
+        .ok_or_else(|| Error::Parse("The epub has no package document".to_string()))?;
+    let mut opf = replace_metadata(&read_entry(&mut archive, &opf_path)?, volume_info)?;
+
+    let cover = match cover {
+        Some(cover) => {
+            let extension = cover
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or("jpg")
+                .to_lowercase();
+            let href = format!("{}.{}", COVER_ID, extension);
+            opf = add_cover(&opf, &href, media_type(&extension));
+
+            let opf_dir = opf_path
+                .rsplit_once('/')
+                .map(|(dir, _)| format!("{}/", dir))
+                .unwrap_or_default();
+            Some((format!("{}{}", opf_dir, href), fs::read(cover)?))
+        }
+        None => None,
+    };
+
+    // NOTE:(akotro) Written next to the book and moved over it, so a failure leaves it intact
+    let part_path = path.with_extension("epub.part");
+    let written = write_epub(&part_path, &mut archive, &opf_path, &opf, cover);
+    if written.is_err() {
+        let _ = fs::remove_file(&part_path);
+        return written;
+    }
+    fs::rename(&part_path, path)?;
+
+    Ok(())
+}
+
+fn write_epub(
+    part_path: &Path,
+    archive: &mut ZipArchive<File>,
+    opf_path: &str,
+    opf: &str,
+    cover: Option<(String, Vec<u8>)>,
+) -> crate::Result<()> {
+    let mut writer = ZipWriter::new(File::create(part_path)?);
+
+    // NOTE:(akotro) Raw copies keep the stored `mimetype` entry first, as readers expect
+    for index in 0..archive.len() {
+        let file = archive.by_index_raw(index)?;
+        let replaced = file.name() == opf_path
+            || cover
+                .as_ref()
+                .is_some_and(|(cover_path, _)| file.name() == cover_path);
+        if !replaced {
+            writer.raw_copy_file(file)?;
+        }
+    }
+
+    writer.start_file(
+        opf_path,
+        FileOptions::default().compression_method(CompressionMethod::Deflated),
+    )?;
+    writer.write_all(opf.as_bytes())?;
+
+    if let Some((cover_path, cover)) = cover {
+        writer.start_file(
+            cover_path,
+            FileOptions::default().compression_method(CompressionMethod::Stored),
+        )?;
+        writer.write_all(&cover)?;
+    }
+
+    writer.finish()?;
+    Ok(())
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> crate::Result<String> {
+    let mut contents = String::new();
+    archive.by_name(name)?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+fn replace_metadata(opf: &str, volume_info: &VolumeInfo) -> crate::Result<String> {
+    let metadata = METADATA
+        .captures(opf)
+        .ok_or_else(|| Error::Parse("The epub has no metadata".to_string()))?;
+    let unique_identifier = PACKAGE_UNIQUE_IDENTIFIER
+        .captures(opf)
+        .map(|captures| captures[1].to_string());
+
+    // NOTE:(akotro) The identifier the package points to has to stay
+    let mut removed_ids = HashSet::new();
+    let kept = REPLACED_ELEMENT.replace_all(&metadata[2], |element: &Captures| {
+        let start_tag = element[0].split('>').next().unwrap_or_default();
+        let id = ID_ATTRIBUTE
+            .captures(start_tag)
+            .map(|captures| captures[1].to_string());
+        if id.is_some() && id == unique_identifier {
+            return element[0].to_string();
+        }
+        removed_ids.extend(id);
+        String::new()
+    });
+    let kept = REFINES_META.replace_all(&kept, |meta: &Captures| {
+        if removed_ids.contains(&meta[1]) {
+            String::new()
+        } else {
+            meta[0].to_string()
+        }
+    });
+
+    let mut start_tag = metadata[1].to_string();
+    if !opf.contains(DC_NAMESPACE) {
+        start_tag.insert_str(
+            start_tag.len() - 1,
+            &format!(r#" xmlns:dc="{}""#, DC_NAMESPACE),
+        );
+    }
+
+    let mut elements = String::new();
+    let mut add_element = |name: &str, value: &str| {
+        elements.push_str(&format!("\n    <dc:{0}>{1}</dc:{0}>", name, escape(value)));
+    };
+    if let Some(title) = &volume_info.title {
+        add_element("title", title);
+    }
+    for author in volume_info.authors.iter().flatten() {
+        add_element("creator", author);
+    }
+    if let Some(publisher) = &volume_info.publisher {
+        add_element("publisher", publisher);
+    }
+    if let Some(published_date) = &volume_info.published_date {
+        add_element("date", published_date);
+    }
+    for identifier in volume_info
+        .industry_identifiers
+        .iter()
+        .flatten()
+        .filter(|identifier| identifier.isbn_type.starts_with("ISBN"))
+    {
+        add_element("identifier", &format!("urn:isbn:{}", identifier.identifier));
+    }
+    if let Some(description) = &volume_info.description {
+        add_element("description", description);
+    }
+
+    let new_metadata = format!(
+        "{}{}\n    {}{}",
+        start_tag,
+        elements,
+        kept.trim_start(),
+        &metadata[3]
+    );
+    Ok(opf.replacen(&metadata[0], &new_metadata, 1))
+}
+
+fn add_cover(opf: &str, href: &str, media_type: &str) -> String {
+    let epub3 = PACKAGE_VERSION_3.is_match(opf);
+
+    // NOTE:(akotro) Only one cover, the previous one stays in the manifest as a plain image
+    let opf = COVER_META.replace_all(opf, "");
+    let opf = COVER_ITEM.replace_all(&opf, "");
+    let opf = COVER_PROPERTY_ONLY.replace_all(&opf, "");
+    let mut opf = COVER_PROPERTY.replace_all(&opf, "$1").into_owned();
+
+    let properties = if epub3 {
+        r#" properties="cover-image""#
+    } else {
+        ""
+    };
+    if let Some(manifest_end) = MANIFEST_END.find(&opf).map(|m| m.start()) {
+        opf.insert_str(
+            manifest_end,
+            &format!(
+                "  <item id=\"{}\" href=\"{}\" media-type=\"{}\"{}/>\n  ",
+                COVER_ID, href, media_type, properties
+            ),
+        );
+    }
+    // NOTE:(akotro) EPUB 3 readers still look for the EPUB 2 meta
+    if let Some(metadata_end) = METADATA
+        .captures(&opf)
+        .and_then(|captures| captures.get(3))
+        .map(|m| m.start())
+    {
+        opf.insert_str(
+            metadata_end,
+            &format!("  <meta name=\"cover\" content=\"{}\"/>\n  ", COVER_ID),
+        );
+    }
+
+    opf
+}
+
+fn media_type(extension: &str) -> &'static str {
+    match extension {
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, process};
+
+    use super::*;
+    use crate::models::IndustryIdentifier;
+
+    const EPUB2_OPF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="BookId">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:title>Uploader Title</dc:title>
+    <dc:creator opf:role="aut">Uploader Author</dc:creator>
+    <dc:identifier id="BookId">urn:uuid:1234</dc:identifier>
+    <dc:identifier opf:scheme="ISBN">0000000000</dc:identifier>
+    <dc:language>en</dc:language>
+    <dc:subject>Fiction</dc:subject>
+    <meta name="cover" content="old-cover"/>
+  </metadata>
+  <manifest>
+    <item id="old-cover" href="images/cover.jpg" media-type="image/jpeg"/>
+    <item id="chapter1" href="chapter1.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine>
+    <itemref idref="chapter1"/>
+  </spine>
+</package>
+"#;
+
+    const EPUB3_OPF: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="pub-id">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="pub-id">urn:uuid:5678</dc:identifier>
+    <meta refines="#pub-id" property="identifier-type" scheme="onix:codelist5">15</meta>
+    <dc:title id="title">Uploader Title</dc:title>
+    <meta refines="#title" property="title-type">main</meta>
+    <dc:creator id="creator01">Uploader Author</dc:creator>
+    <meta refines="#creator01" property="role" scheme="marc:relators">aut</meta>
+    <meta refines="#creator01" property="file-as">Author, Uploader</meta>
+    <dc:language>en</dc:language>
+    <meta property="dcterms:modified">2020-01-01T00:00:00Z</meta>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="cover" href="cover.png" media-type="image/png" properties="cover-image"/>
+    <item id="chapter1" href="chapter1.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine>
+    <itemref idref="chapter1"/>
+  </spine>
+</package>
+"##;
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+    fn volume_info() -> VolumeInfo {
+        VolumeInfo {
+            title: Some("Canonical Title".to_string()),
+            authors: Some(vec![
+                "First Author".to_string(),
+                "Second Author".to_string(),
+            ]),
+            publisher: Some("Publisher".to_string()),
+            published_date: Some("2001-02-03".to_string()),
+            description: Some("A description.".to_string()),
+            industry_identifiers: Some(vec![
+                IndustryIdentifier {
+                    isbn_type: "ISBN_13".to_string(),
+                    identifier: "9780000000002".to_string(),
+                },
+                IndustryIdentifier {
+                    isbn_type: "OTHER".to_string(),
+                    identifier: "OCLC:1".to_string(),
+                },
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn metadata(opf: &str) -> String {
+        METADATA.captures(opf).unwrap()[2].to_string()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rubook-epub-{}-{}", process::id(), name))
+    }
+
+    fn write_fixture(path: &Path, opf: &str) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        writer.start_file("mimetype", stored).unwrap();
+        writer.write_all(b"application/epub+zip").unwrap();
+        writer.start_file(CONTAINER_PATH, deflated).unwrap();
+        writer.write_all(CONTAINER.as_bytes()).unwrap();
+        writer.start_file("OEBPS/content.opf", deflated).unwrap();
+        writer.write_all(opf.as_bytes()).unwrap();
+        writer.start_file("OEBPS/chapter1.xhtml", deflated).unwrap();
+        writer
+            .write_all(b"<html><body>Chapter 1</body></html>")
+            .unwrap();
+        writer.start_file("OEBPS/images/cover.jpg", stored).unwrap();
+        writer.write_all(b"old cover").unwrap();
+        writer.finish().unwrap();
+    }
+
+    fn read(path: &Path, name: &str) -> Vec<u8> {
+        let mut archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
+        let mut contents = Vec::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[test]
+    fn replaces_epub2_metadata() {
+        let opf = replace_metadata(EPUB2_OPF, &volume_info()).unwrap();
+        let metadata = metadata(&opf);
+
+        assert!(metadata.contains("<dc:title>Canonical Title</dc:title>"));
+        assert!(metadata.contains("<dc:creator>First Author</dc:creator>"));
+        assert!(metadata.contains("<dc:creator>Second Author</dc:creator>"));
+        assert!(metadata.contains("<dc:publisher>Publisher</dc:publisher>"));
+        assert!(metadata.contains("<dc:date>2001-02-03</dc:date>"));
+        assert!(metadata.contains("<dc:identifier>urn:isbn:9780000000002</dc:identifier>"));
+        assert!(metadata.contains("<dc:description>A description.</dc:description>"));
+        assert!(!metadata.contains("OCLC:1"));
+        assert!(!metadata.contains("Uploader"));
+        assert!(!metadata.contains("0000000000"));
+
+        // NOTE:(akotro) The package's own identifier and the other metadata stay
+        assert!(metadata.contains(r#"<dc:identifier id="BookId">urn:uuid:1234</dc:identifier>"#));
+        assert!(metadata.contains("<dc:language>en</dc:language>"));
+        assert!(metadata.contains("<dc:subject>Fiction</dc:subject>"));
+        assert!(metadata.contains(r#"<meta name="cover" content="old-cover"/>"#));
+        assert_eq!(
+            opf.split_once("</metadata>").unwrap().1,
+            EPUB2_OPF.split_once("</metadata>").unwrap().1
+        );
+    }
+
+    #[test]
+    fn removes_refines_of_replaced_epub3_elements() {
+        let opf = replace_metadata(EPUB3_OPF, &volume_info()).unwrap();
+        let metadata = metadata(&opf);
+
+        assert!(!metadata.contains("#title"));
+        assert!(!metadata.contains("#creator01"));
+        assert!(!metadata.contains("Uploader"));
+        assert!(metadata.contains(r##"<meta refines="#pub-id" property="identifier-type""##));
+        assert!(metadata.contains(r#"<dc:identifier id="pub-id">urn:uuid:5678</dc:identifier>"#));
+        assert!(metadata.contains(r#"<meta property="dcterms:modified">"#));
+        assert!(metadata.contains("<dc:title>Canonical Title</dc:title>"));
+    }
+
+    #[test]
+    fn adds_the_dc_namespace_when_missing() {
+        let opf = EPUB2_OPF.replace(r#" xmlns:dc="http://purl.org/dc/elements/1.1/""#, "");
+        let opf = replace_metadata(&opf, &volume_info()).unwrap();
+        assert!(opf.contains(r#"<metadata xmlns:opf="http://www.idpf.org/2007/opf" xmlns:dc="http://purl.org/dc/elements/1.1/">"#));
+    }
+
+    #[test]
+    fn escapes_values() {
+        let volume_info = VolumeInfo {
+            title: Some("Pride & <Prejudice>".to_string()),
+            authors: Some(vec!["Jane \"J.\" Austen".to_string()]),
+            ..Default::default()
+        };
+        let opf = replace_metadata(EPUB2_OPF, &volume_info).unwrap();
+
+        assert!(opf.contains("<dc:title>Pride &amp; &lt;Prejudice&gt;</dc:title>"));
+        assert!(opf.contains("<dc:creator>Jane &quot;J.&quot; Austen</dc:creator>"));
+    }
+
+    #[test]
+    fn fails_without_metadata() {
+        assert!(matches!(
+            replace_metadata("<package></package>", &volume_info()),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn replaces_an_existing_epub2_cover() {
+        let opf = add_cover(EPUB2_OPF, "rubook-cover.jpg", "image/jpeg");
+
+        assert!(!opf.contains(r#"content="old-cover""#));
+        assert_eq!(opf.matches(r#"<meta name="cover""#).count(), 1);
+        assert!(opf.contains(r#"<meta name="cover" content="rubook-cover"/>"#));
+        assert!(opf.contains(
+            r#"<item id="rubook-cover" href="rubook-cover.jpg" media-type="image/jpeg"/>"#
+        ));
+        assert!(opf.contains(r#"<item id="old-cover" href="images/cover.jpg""#));
+    }
+
+    #[test]
+    fn replaces_an_existing_epub3_cover() {
+        let with_nav_cover =
+            EPUB3_OPF.replace(r#"properties="nav""#, r#"properties="nav cover-image""#);
+        let opf = add_cover(&with_nav_cover, "rubook-cover.png", "image/png");
+
+        assert_eq!(opf.matches("cover-image").count(), 1);
+        assert!(opf.contains(r#"<item id="rubook-cover" href="rubook-cover.png" media-type="image/png" properties="cover-image"/>"#));
+        assert!(opf.contains(r#"<item id="cover" href="cover.png" media-type="image/png"/>"#));
+        assert!(opf
+            .contains(r#"href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>"#));
+        assert!(opf.contains(r#"<meta name="cover" content="rubook-cover"/>"#));
+
+        // NOTE:(akotro) Embedding again replaces the cover instead of adding a second one
+        let opf = add_cover(&opf, "rubook-cover.jpg", "image/jpeg");
+        assert_eq!(opf.matches(r#"id="rubook-cover""#).count(), 1);
+        assert_eq!(opf.matches(r#"<meta name="cover""#).count(), 1);
+        assert!(opf.contains(r#"href="rubook-cover.jpg""#));
+    }
+
+    #[test]
+    fn round_trips_through_the_zip() {
+        let path = temp_path("round-trip.epub");
+        let cover_path = temp_path("cover.png");
+        write_fixture(&path, EPUB2_OPF);
+        fs::write(&cover_path, b"new cover").unwrap();
+
+        write_metadata(&path, &volume_info(), Some(&cover_path)).unwrap();
+        write_metadata(&path, &volume_info(), Some(&cover_path)).unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let names = archive.file_names().map(String::from).collect::<Vec<_>>();
+        let mimetype = archive.by_index(0).unwrap();
+        assert_eq!(mimetype.name(), "mimetype");
+        assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+        drop(mimetype);
+        assert_eq!(names.len(), 6);
+        for name in [
+            CONTAINER_PATH,
+            "OEBPS/content.opf",
+            "OEBPS/chapter1.xhtml",
+            "OEBPS/images/cover.jpg",
+            "OEBPS/rubook-cover.png",
+        ] {
+            assert!(names.iter().any(|n| n == name), "{} is missing", name);
+        }
+        for index in 0..archive.len() {
+            let mut file = archive.by_index(index).unwrap();
+            std::io::copy(&mut file, &mut std::io::sink()).unwrap();
+        }
+
+        let opf = String::from_utf8(read(&path, "OEBPS/content.opf")).unwrap();
+        assert!(opf.contains("<dc:title>Canonical Title</dc:title>"));
+        assert_eq!(opf.matches(r#"id="rubook-cover""#).count(), 1);
+        assert!(opf.contains(r#"href="rubook-cover.png" media-type="image/png""#));
+        assert_eq!(read(&path, "OEBPS/rubook-cover.png"), b"new cover");
+        assert_eq!(
+            read(&path, "OEBPS/chapter1.xhtml"),
+            b"<html><body>Chapter 1</body></html>"
+        );
+        assert!(!path.with_extension("epub.part").exists());
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(cover_path).unwrap();
+    }
+
+    #[test]
+    fn leaves_the_book_intact_when_it_fails() {
+        let path = temp_path("no-metadata.epub");
+        write_fixture(&path, "<package></package>");
+        let before = fs::read(&path).unwrap();
+
+        assert!(write_metadata(&path, &volume_info(), None).is_err());
+        assert_eq!(fs::read(&path).unwrap(), before);
+
+        fs::remove_file(path).unwrap();
+    }
+}
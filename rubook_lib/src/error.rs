@@ -0,0 +1,162 @@
+use std::fmt;
+
+use inquire::InquireError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A request to a mirror, Google Books or the backend failed
+    Network(reqwest::Error),
+    /// The mirror answered with a block page
+    MirrorBlocked(String),
+    /// None of the mirrors could be reached
+    NoWorkingMirrors,
+    /// There is no way to download from this mirror
+    UnsupportedMirror(String),
+    /// A page, url or response body couldn't be parsed
+    Parse(String),
+    /// The download key couldn't be found on the mirror's download page
+    KeyNotFound(String),
+    /// The connection closed before the whole file was received, the `.part` file is kept
+    IncompleteDownload {
+        received: u64,
+        expected: u64,
+    },
+    /// The downloaded file doesn't hash to the book's md5, it has been deleted
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+    /// The backend answered with an unsuccessful `ApiResponse`
+    Api {
+        status: Option<u16>,
+        message: String,
+    },
+    /// A local file couldn't be read or written
+    Io(std::io::Error),
+    /// An interactive prompt was cancelled or couldn't be shown
+    Prompt(InquireError),
+    /// The local library index couldn't be read or written
+    Database(rusqlite::Error),
+    /// Nothing matched, e.g. a search without results or a url that isn't an image
+    NotFound(String),
+}
+
+impl Error {
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(
+            self,
+            Error::Api {
+                status: Some(401),
+                ..
+            }
+        )
+    }
+
+    /// Whether the error is specific to the mirror that was used, so another one might work.
+    pub fn is_mirror_failure(&self) -> bool {
+        matches!(
+            self,
+            Error::Network(_)
+                | Error::MirrorBlocked(_)
+                | Error::UnsupportedMirror(_)
+                | Error::Parse(_)
+                | Error::KeyNotFound(_)
+                | Error::IncompleteDownload { .. }
+                | Error::ChecksumMismatch { .. }
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Network(e) => write!(f, "Network error: {}", e),
+            Error::MirrorBlocked(mirror) => write!(f, "Mirror is blocked: {}", mirror),
+            Error::NoWorkingMirrors => write!(f, "Couldn't reach mirrors"),
+            Error::UnsupportedMirror(mirror) => write!(f, "Can't download from mirror: {}", mirror),
+            Error::Parse(message) => write!(f, "Parse error: {}", message),
+            Error::KeyNotFound(mirror) => write!(f, "Couldn't find download key on {}", mirror),
+            Error::IncompleteDownload { received, expected } => write!(
+                f,
+                "Download stopped after {} of {} bytes",
+                received, expected
+            ),
+            Error::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Downloaded file is corrupted, expected md5 {} but got {}",
+                expected, actual
+            ),
+            Error::Api {
+                status: Some(status),
+                message,
+            } => write!(f, "Backend error ({}): {}", status, message),
+            Error::Api {
+                status: None,
+                message,
+            } => write!(f, "Backend error: {}", message),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Prompt(e) => write!(f, "Prompt error: {}", e),
+            Error::Database(e) => write!(f, "Library index error: {}", e),
+            Error::NotFound(what) => write!(f, "Not found: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Prompt(e) => Some(e),
+            Error::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Network(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Parse(error.to_string())
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(error: url::ParseError) -> Self {
+        Error::Parse(error.to_string())
+    }
+}
+
+impl From<InquireError> for Error {
+    fn from(error: InquireError) -> Self {
+        Error::Prompt(error)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(error: zip::result::ZipError) -> Self {
+        match error {
+            zip::result::ZipError::Io(e) => Error::Io(e),
+            e => Error::Parse(e.to_string()),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(error: rusqlite::Error) -> Self {
+        Error::Database(error)
+    }
+}
@@ -1,5 +1,15 @@
 pub mod backend_util;
+pub mod covers;
+pub mod epub;
+pub mod error;
 pub mod libgen;
 pub mod libgen_util;
+pub mod library;
+pub mod library_index;
 pub mod models;
 pub mod user;
+
+#[cfg(test)]
+mod test_util;
+
+pub use error::{Error, Result};
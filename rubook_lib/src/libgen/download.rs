@@ -1,15 +1,19 @@
-use bytes::Bytes;
-use regex::bytes::Regex;
-use reqwest::{Url, Client};
 use lazy_static::lazy_static;
+use regex::bytes::Regex;
+use reqwest::{Client, Url};
 
-use super::{mirrors::Mirror, models::LibgenBook};
+use super::{
+    mirrors::Mirror,
+    models::{LibgenBook, Topic},
+};
+use crate::error::Error;
 
 lazy_static! {
     static ref KEY_REGEX: Regex = Regex::new(r"get\.php\?md5=\w{32}&key=\w{16}").unwrap();
-    static ref KEY_REGEX_LOL: Regex =
-        Regex::new(r"http://62\.182\.86\.140/main/\d{7}/\w{32}/.+?(gz|pdf|rar|djvu|epub|chm)")
-            .unwrap();
+    static ref KEY_REGEX_LOL: Regex = Regex::new(
+        r"http://62\.182\.86\.140/(main|fiction)/\d{7}/\w{32}/.+?(gz|pdf|rar|djvu|epub|chm)"
+    )
+    .unwrap();
     static ref KEY_REGEX_LOL_CLOUDFLARE: Regex = Regex::new(
         r"https://cloudflare-ipfs\.com/ipfs/\w{62}\?filename=.+?(gz|pdf|rar|djvu|epub|chm)"
     )
@@ -19,145 +23,195 @@ lazy_static! {
             .unwrap();
 }
 
-pub async fn download_book(
-    client: &Client,
-    mirror: &Mirror,
-    book: &LibgenBook,
-) -> Result<reqwest::Response, &'static str> {
-    let download_page_url_md5 =
-        mirror
-        .download_pattern
-        .as_ref()
-        .unwrap()
-        .replace("{md5}", &book.md5);
-    let download_page_url = Url::parse(&download_page_url_md5).unwrap();
-
-    let content = client
-        .get(download_page_url)
-        .send()
-        .await
-        .or(Err("Couldn't connect to mirror"))?
-        .bytes()
-        .await
-        .or(Err("Couldn't get mirror page"))?;
-
-    match mirror.host_url.as_str() {
-        "https://libgen.rocks/" => match download_book_from_ads(&content, mirror, client).await {
-            Ok(b) => Ok(b),
-            Err(_e) => Err("Download error"),
-        },
-        "http://libgen.lc/" => match download_book_from_ads(&content, mirror, client).await {
-            Ok(b) => Ok(b),
-            Err(_e) => Err("Download error"),
-        },
-        "http://library.lol/" => match download_book_from_lol(&content, mirror, client).await {
-            Ok(b) => Ok(b),
-            Err(_e) => Err("Download error"),
-        },
-        "http://libgen.me/" => match download_book_from_lol(&content, mirror, client).await {
-            Ok(b) => Ok(b),
-            Err(_e) => Err("Download error"),
-        },
-        &_ => Err("Couldn't find download url"),
+/// Turns the download page of a mirror into the url the file itself is served from.
+///
+/// A mirror names its resolver in the `resolver` column of the backend or the `Resolver` key
+/// of `mirrors.json`, so a new host that serves one of the known pages only needs a record.
+pub trait DownloadResolver: Send + Sync {
+    fn resolve(&self, download_page: &[u8], mirror: &Mirror) -> crate::Result<Url>;
+}
+
+/// `ads.php` / `get.php` pages (libgen.rocks, libgen.lc) that link to `get.php?md5=..&key=..`.
+pub struct AdsResolver;
+
+/// library.lol style pages that link to the file directly, with the ipfs gateways as fallback.
+pub struct LolResolver;
+
+/// Pages that only link to the file on an ipfs gateway.
+pub struct IpfsResolver;
+
+impl DownloadResolver for AdsResolver {
+    fn resolve(&self, download_page: &[u8], mirror: &Mirror) -> crate::Result<Url> {
+        let key = find_key(&KEY_REGEX, download_page)
+            .ok_or_else(|| Error::KeyNotFound(mirror.host_url.clone()))?;
+
+        let download_url = Url::parse(mirror.host_url.as_ref())?;
+        let options = Url::options();
+        let base_url = options.base_url(Some(&download_url));
+        Ok(base_url.parse(key)?)
     }
 }
 
-pub async fn download_book_fiction(
-    client: &Client,
-    mirror: &Mirror,
-    md5: &String,
-) -> Result<reqwest::Response, &'static str> {
-    let download_page_url_md5 =
-        mirror
-        .download_pattern
-        .as_ref()
-        .unwrap()
-        .replace("{md5}", &md5);
-    let download_page_url = Url::parse(&download_page_url_md5).unwrap();
-
-    let content = client
-        .get(download_page_url)
-        .send()
-        .await
-        .or(Err("Couldn't connect to mirror"))?
-        .bytes()
-        .await
-        .or(Err("Couldn't get mirror page"))?;
-
-    match mirror.host_url.as_str() {
-        "https://libgen.rocks/" => match download_book_from_ads(&content, mirror, client).await {
-            Ok(b) => Ok(b),
-            Err(_e) => Err("Download error"),
-        },
-        "http://libgen.lc/" => match download_book_from_ads(&content, mirror, client).await {
-            Ok(b) => Ok(b),
-            Err(_e) => Err("Download error"),
-        },
-        "http://library.lol/" => match download_book_from_lol(&content, mirror, client).await {
-            Ok(b) => Ok(b),
-            Err(_e) => Err("Download error"),
-        },
-        "http://libgen.me/" => match download_book_from_lol(&content, mirror, client).await {
-            Ok(b) => Ok(b),
-            Err(_e) => Err("Download error"),
-        },
-        &_ => Err("Couldn't find download url"),
+impl DownloadResolver for LolResolver {
+    fn resolve(&self, download_page: &[u8], mirror: &Mirror) -> crate::Result<Url> {
+        match find_key(&KEY_REGEX_LOL, download_page) {
+            Some(key) => Ok(Url::parse(key)?),
+            None => IpfsResolver.resolve(download_page, mirror),
+        }
     }
 }
 
-async fn download_book_from_ads(
-    download_page: &Bytes,
-    mirror: &Mirror,
-    client: &Client,
-) -> Result<reqwest::Response, &'static str> {
-    let key = KEY_REGEX
-        .captures(download_page)
-        .map(|c| std::str::from_utf8(c.get(0).unwrap().as_bytes()).unwrap());
-    if key.is_none() {
-        return Err("Couldn't find download key");
+impl DownloadResolver for IpfsResolver {
+    fn resolve(&self, download_page: &[u8], mirror: &Mirror) -> crate::Result<Url> {
+        let key = find_key(&KEY_REGEX_LOL_CLOUDFLARE, download_page)
+            .or_else(|| find_key(&KEY_REGEX_LOL_IPFS, download_page))
+            .ok_or_else(|| Error::KeyNotFound(mirror.host_url.clone()))?;
+        Ok(Url::parse(key)?)
     }
+}
 
-    let download_url = Url::parse(mirror.host_url.as_ref()).unwrap();
-    let options = Url::options();
-    let base_url = options.base_url(Some(&download_url));
-    let download_url = base_url.parse(key.unwrap()).unwrap();
-    client
-        .get(download_url)
-        .send()
-        .await
-        .or(Err("Couldn't connect to mirror"))
+/// The built-in resolver registered under `name`.
+pub fn resolver_by_name(name: &str) -> Option<&'static dyn DownloadResolver> {
+    match name {
+        "ads" => Some(&AdsResolver),
+        "library.lol" => Some(&LolResolver),
+        "ipfs" => Some(&IpfsResolver),
+        _ => None,
+    }
 }
 
-async fn download_book_from_lol(
-    download_page: &Bytes,
-    mirror: &Mirror,
+/// Follows the mirror's download page to the url the file itself is served from.
+pub async fn resolve_download_url(
     client: &Client,
-) -> Result<reqwest::Response, &'static str> {
-    let mut key = KEY_REGEX_LOL
-        .captures(download_page)
-        .map(|c| std::str::from_utf8(c.get(0).unwrap().as_bytes()).unwrap());
-    if key.is_none() {
-        key = KEY_REGEX_LOL_CLOUDFLARE
-            .captures(download_page)
-            .map(|c| std::str::from_utf8(c.get(0).unwrap().as_bytes()).unwrap());
+    mirror: &Mirror,
+    book: &LibgenBook,
+) -> crate::Result<Url> {
+    let resolver = mirror
+        .resolver
+        .as_deref()
+        .and_then(resolver_by_name)
+        .ok_or_else(|| Error::UnsupportedMirror(mirror.host_url.clone()))?;
+    let download_page_url = download_page_url(mirror, book)?;
+
+    let content = client.get(download_page_url).send().await?.bytes().await?;
+
+    resolver.resolve(&content, mirror)
+}
+
+/// The mirror's download page of the book, fiction and non-fiction have their own.
+fn download_page_url(mirror: &Mirror, book: &LibgenBook) -> crate::Result<Url> {
+    let pattern = match book.topic {
+        Topic::NonFiction => mirror.download_pattern.clone(),
+        // NOTE:(akotro) Mirrors keep no fiction pattern, the md5 ends the fiction download url
+        Topic::Fiction => mirror
+            .download_url_fiction
+            .as_ref()
+            .map(|download_url| format!("{}{{md5}}", download_url)),
+    };
+    let pattern = pattern.ok_or_else(|| Error::UnsupportedMirror(mirror.host_url.clone()))?;
+    Ok(Url::parse(&pattern.replace("{md5}", &book.md5))?)
+}
+
+fn find_key<'a>(regex: &Regex, download_page: &'a [u8]) -> Option<&'a str> {
+    regex
+        .find(download_page)
+        .and_then(|m| std::str::from_utf8(m.as_bytes()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::serve;
+
+    const MD5: &str = "0123456789ABCDEF0123456789ABCDEF";
+    const IPFS_URL: &str = "https://ipfs.io/ipfs/bafykbzacedp7fyk5bzpiwcqdvb4jdwh5j7wuzqkvrrn55aaaaaaaaaaaaaaaa?filename=A%20Book.epub";
+
+    fn lol_mirror(address: &str) -> Mirror {
+        Mirror {
+            host_url: address.to_string(),
+            download_url: Some(format!("{}/main/", address)),
+            download_url_fiction: Some(format!("{}/fiction/", address)),
+            download_pattern: Some(format!("{}/main/{{md5}}", address)),
+            resolver: Some("library.lol".to_string()),
+            ..Default::default()
+        }
     }
-    if key.is_none() {
-        key = KEY_REGEX_LOL_IPFS
-            .captures(download_page)
-            .map(|c| std::str::from_utf8(c.get(0).unwrap().as_bytes()).unwrap());
+
+    fn book(topic: Topic) -> LibgenBook {
+        LibgenBook {
+            md5: MD5.to_string(),
+            topic,
+            ..Default::default()
+        }
     }
-    if key.is_none() {
-        return Err("Couldn't find download key");
+
+    #[tokio::test]
+    async fn resolves_fiction_through_the_fiction_download_page() {
+        let page = r#"<h2><a href="http://62.182.86.140/fiction/1234567/0123456789abcdef0123456789abcdef/A%20Book.epub">GET</a></h2>"#;
+        let (address, mut requested_paths) = serve("200 OK", page).await;
+
+        let url =
+            resolve_download_url(&Client::new(), &lol_mirror(&address), &book(Topic::Fiction))
+                .await
+                .unwrap();
+
+        assert_eq!(
+            requested_paths.recv().await.unwrap(),
+            format!("/fiction/{}", MD5)
+        );
+        assert_eq!(
+            url.as_str(),
+            "http://62.182.86.140/fiction/1234567/0123456789abcdef0123456789abcdef/A%20Book.epub"
+        );
     }
 
-    let download_url = Url::parse(mirror.host_url.as_ref()).unwrap();
+    #[tokio::test]
+    async fn resolves_non_fiction_through_the_download_pattern() {
+        let page = r#"<h2><a href="http://62.182.86.140/main/1234000/0123456789abcdef0123456789abcdef/A%20Book.pdf">GET</a></h2>"#;
+        let (address, mut requested_paths) = serve("200 OK", page).await;
 
-    let options = Url::options();
-    let base_url = options.base_url(Some(&download_url));
-    let download_url = base_url.parse(key.unwrap()).unwrap();
-    client
-        .get(download_url)
-        .send()
+        let url = resolve_download_url(
+            &Client::new(),
+            &lol_mirror(&address),
+            &book(Topic::NonFiction),
+        )
         .await
-        .or(Err("Couldn't connect to mirror"))
+        .unwrap();
+
+        assert_eq!(
+            requested_paths.recv().await.unwrap(),
+            format!("/main/{}", MD5)
+        );
+        assert_eq!(
+            url.as_str(),
+            "http://62.182.86.140/main/1234000/0123456789abcdef0123456789abcdef/A%20Book.pdf"
+        );
+    }
+
+    #[test]
+    fn lol_resolver_falls_back_to_ipfs() {
+        let page = format!(r#"<li><a href="{}">IPFS.io</a></li>"#, IPFS_URL);
+        let url = LolResolver
+            .resolve(page.as_bytes(), &lol_mirror("http://library.lol"))
+            .unwrap();
+        assert_eq!(url.as_str(), IPFS_URL);
+    }
+
+    #[test]
+    fn fiction_needs_a_fiction_download_url() {
+        let mirror = Mirror {
+            download_url_fiction: None,
+            ..lol_mirror("http://library.lol")
+        };
+        assert!(matches!(
+            download_page_url(&mirror, &book(Topic::Fiction)),
+            Err(Error::UnsupportedMirror(_))
+        ));
+        assert_eq!(
+            download_page_url(&mirror, &book(Topic::NonFiction))
+                .unwrap()
+                .as_str(),
+            format!("http://library.lol/main/{}", MD5)
+        );
+    }
 }
@@ -0,0 +1,133 @@
+use std::future::Future;
+
+use reqwest::Client;
+
+use super::{
+    mirrors::Mirror,
+    models::LibgenBook,
+    search::{search_fiction, search_non_fiction, SearchOptions},
+};
+use crate::{error::Error, models::Book};
+
+/// Runs `attempt` against each mirror in order until one succeeds, moving on to the next one
+/// when a mirror fails (connection errors, block pages, missing download keys, bad files).
+/// Returns the result together with the mirror that produced it.
+pub async fn try_mirrors<'a, T, F, Fut>(
+    mirrors: &'a [Mirror],
+    mut attempt: F,
+) -> crate::Result<(T, &'a Mirror)>
+where
+    F: FnMut(&'a Mirror) -> Fut,
+    Fut: Future<Output = crate::Result<T>>,
+{
+    for mirror in mirrors {
+        match attempt(mirror).await {
+            Ok(value) => return Ok((value, mirror)),
+            Err(e) if e.is_mirror_failure() => {
+                eprintln!("{} failed: {}", mirror, e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(Error::NoWorkingMirrors)
+}
+
+/// Moves `preferred` to the front of the ranked mirrors, so it's tried first.
+pub fn with_preferred(mirrors: &[Mirror], preferred: &Mirror) -> Vec<Mirror> {
+    let mut ranked = vec![preferred.clone()];
+    ranked.extend(
+        mirrors
+            .iter()
+            .filter(|mirror| mirror.host_url != preferred.host_url)
+            .cloned(),
+    );
+    ranked
+}
+
+pub async fn search_non_fiction_failover<'a>(
+    book: &Book,
+    mirrors: &'a [Mirror],
+    client: &Client,
+    options: &SearchOptions,
+) -> crate::Result<(Vec<LibgenBook>, &'a Mirror)> {
+    try_mirrors(mirrors, |mirror| {
+        search_non_fiction(book, mirror, client, options)
+    })
+    .await
+}
+
+pub async fn search_fiction_failover<'a>(
+    book: &Book,
+    mirrors: &'a [Mirror],
+    client: &Client,
+    options: &SearchOptions,
+) -> crate::Result<(Vec<LibgenBook>, &'a Mirror)> {
+    try_mirrors(mirrors, |mirror| {
+        search_fiction(book, mirror, client, options)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::VolumeInfo, test_util::serve};
+
+    const NON_FICTION: &str = include_str!("../../tests/fixtures/non_fiction_simple.html");
+
+    async fn search_mirror(status: &'static str, body: &'static str) -> Mirror {
+        let (host_url, _) = serve(status, body).await;
+        Mirror {
+            search_url: Some(format!("{}/search.php", host_url)),
+            host_url,
+            ..Default::default()
+        }
+    }
+
+    fn book() -> Book {
+        Book {
+            volume_info: VolumeInfo {
+                title: Some(String::from("The Rust Programming Language")),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn search_moves_past_blocked_and_failing_mirrors() {
+        let mirrors = vec![
+            search_mirror("403 Forbidden", "<title>Block Page</title>").await,
+            search_mirror("200 OK", "<title>Block Page</title>").await,
+            search_mirror("503 Service Unavailable", "").await,
+            search_mirror("200 OK", NON_FICTION).await,
+        ];
+
+        let (books, mirror) = search_non_fiction_failover(
+            &book(),
+            &mirrors,
+            &Client::new(),
+            &SearchOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(mirror.host_url, mirrors[3].host_url);
+        assert_eq!(books[0].md5, "1A3C6D0B6A1E08E5D8C5C3F0A1C8B7E2");
+    }
+
+    #[tokio::test]
+    async fn search_fails_when_every_mirror_is_blocked() {
+        let mirrors = vec![search_mirror("403 Forbidden", "<title>Block Page</title>").await];
+
+        let result = search_non_fiction_failover(
+            &book(),
+            &mirrors,
+            &Client::new(),
+            &SearchOptions::default(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::NoWorkingMirrors)));
+    }
+}
@@ -1,11 +1,23 @@
 use core::fmt;
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use reqwest::{Client, StatusCode};
+use futures::{
+    future::{join_all, BoxFuture, Shared},
+    FutureExt,
+};
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio::task::JoinHandle;
 
+use crate::error::Error;
+
+/// How long a single mirror gets to answer a health check.
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub enum MirrorType {
     Search,
     Download,
@@ -21,6 +33,8 @@ pub struct Mirror {
     pub download_pattern: Option<String>,
     pub sync_url: Option<String>,
     pub cover_pattern: Option<String>,
+    /// Name of the [`DownloadResolver`](super::download::DownloadResolver) for the download page
+    pub resolver: Option<String>,
 }
 
 impl fmt::Display for Mirror {
@@ -29,19 +43,107 @@ impl fmt::Display for Mirror {
     }
 }
 
+/// Outcome of checking whether a mirror can be used.
+#[derive(Debug, Clone, Serialize)]
+pub struct MirrorHealth {
+    pub mirror: Mirror,
+    /// Http status of the answer, `None` when the mirror couldn't be reached
+    pub status: Option<u16>,
+    /// Time until the whole answer was received
+    #[serde(rename = "latency_ms", serialize_with = "serialize_latency")]
+    pub latency: Option<Duration>,
+    pub reachable: bool,
+    pub blocked: bool,
+    pub error: Option<String>,
+}
+
+impl MirrorHealth {
+    /// Reachable, not blocked and answering with a success status.
+    pub fn is_working(&self) -> bool {
+        self.reachable
+            && !self.blocked
+            && self
+                .status
+                .is_some_and(|status| (200..300).contains(&status))
+    }
+}
+
+fn serialize_latency<S>(latency: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    latency
+        .map(|latency| latency.as_millis() as u64)
+        .serialize(serializer)
+}
+
+/// Working mirrors first, fastest to slowest, then the blocked and unreachable ones.
+fn rank_health(health: &mut [MirrorHealth]) {
+    health.sort_by_key(|h| {
+        (
+            !h.is_working(),
+            !h.blocked,
+            h.latency.unwrap_or(Duration::MAX),
+        )
+    });
+}
+
 impl Mirror {
-    pub async fn check_connection(&self, client: &Client) -> Result<(), StatusCode> {
-        let response = client.get(self.host_url.as_str()).send().await;
-        match response {
-            Ok(res) => {
-                let text = res.text().await.unwrap();
+    pub async fn check_health(&self, client: &Client, timeout: Duration) -> MirrorHealth {
+        let mut health = MirrorHealth {
+            mirror: self.clone(),
+            status: None,
+            latency: None,
+            reachable: false,
+            blocked: false,
+            error: None,
+        };
+
+        let start = Instant::now();
+        let response = match client
+            .get(self.host_url.as_str())
+            .timeout(timeout)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                health.error = Some(Error::from(e).to_string());
+                return health;
+            }
+        };
+        health.status = Some(response.status().as_u16());
+        // NOTE:(akotro) Error pages, e.g. 403 or 503, are answers too but the mirror can't be used
+        let status_error = response.error_for_status_ref().err();
+
+        match response.text().await {
+            Ok(text) => {
+                health.latency = Some(start.elapsed());
+                health.reachable = true;
                 if text.contains("Block Page") {
-                    Err(StatusCode::FORBIDDEN)
-                } else {
-                    Ok(())
+                    health.blocked = true;
+                    health.error = Some(Error::MirrorBlocked(self.host_url.clone()).to_string());
+                } else if let Some(e) = status_error {
+                    health.error = Some(Error::from(e).to_string());
                 }
             }
-            Err(e) => Err(e.status().unwrap()),
+            Err(e) => health.error = Some(Error::from(e).to_string()),
+        }
+        health
+    }
+
+    pub async fn check_connection(&self, client: &Client) -> crate::Result<()> {
+        let text = client
+            .get(self.host_url.as_str())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        if text.contains("Block Page") {
+            Err(Error::MirrorBlocked(self.host_url.clone()))
+        } else {
+            Ok(())
         }
     }
 }
@@ -57,10 +159,10 @@ impl MirrorList {
         let mut download_mirrors = Vec::new();
 
         for mirror in mirrors {
-            if mirror.host_url != "" {
-                if let Some(_) = mirror.search_url {
+            if !mirror.host_url.is_empty() {
+                if mirror.search_url.is_some() {
                     search_mirrors.push(mirror);
-                } else if let Some(_) = mirror.download_url {
+                } else if mirror.download_url.is_some() {
                     download_mirrors.push(mirror);
                 }
             }
@@ -72,13 +174,16 @@ impl MirrorList {
         }
     }
 
-    pub fn parse_mirrors(json: &str) -> MirrorList {
+    pub fn parse_mirrors(json: &str) -> crate::Result<MirrorList> {
         let mut search_mirrors: Vec<Mirror> = Vec::new();
         let mut download_mirrors: Vec<Mirror> = Vec::new();
 
-        let map: Value = serde_json::from_str(json).unwrap();
+        let map: Value = serde_json::from_str(json)?;
+        let map = map
+            .as_object()
+            .ok_or_else(|| Error::Parse("Expected a json object of mirrors".to_string()))?;
 
-        map.as_object().unwrap().iter().for_each(|(_k, v)| {
+        map.iter().for_each(|(_k, v)| {
             let search_url = v.get("SearchUrl").map(|v| v.to_string());
             let search_url_fiction = v.get("FictionSearchUrl").map(|v| v.to_string());
             let host_url = v.get("Host").map(|v| v.to_string());
@@ -91,10 +196,11 @@ impl MirrorList {
             let download_pattern = v.get("NonFictionDownloadUrl").map(|v| v.to_string());
             let sync_url = v.get("NonFictionSynchronizationUrl").map(|v| v.to_string());
             let cover_pattern = v.get("NonFictionCoverUrl").map(|v| v.to_string());
-            if let Some(..) = host_url {
+            let resolver = v.get("Resolver").and_then(|v| v.as_str()).map(String::from);
+            if let Some(host_url) = host_url {
                 if search_url.is_some() {
                     search_mirrors.push(Mirror {
-                        host_url: host_url.unwrap(),
+                        host_url,
                         search_url,
                         search_url_fiction,
                         download_url,
@@ -102,10 +208,11 @@ impl MirrorList {
                         download_pattern,
                         sync_url,
                         cover_pattern,
+                        resolver,
                     })
                 } else if download_url.is_some() {
                     download_mirrors.push(Mirror {
-                        host_url: host_url.unwrap(),
+                        host_url,
                         search_url,
                         search_url_fiction,
                         download_url,
@@ -113,91 +220,228 @@ impl MirrorList {
                         download_pattern,
                         sync_url,
                         cover_pattern,
+                        resolver,
                     })
                 }
             }
         });
 
-        MirrorList {
+        Ok(MirrorList {
             search_mirrors,
             download_mirrors,
-        }
+        })
     }
 
+    /// Checks all mirrors of a type at once, ranked with the fastest working mirror first.
+    pub async fn check_mirrors(
+        &self,
+        mirror_type: MirrorType,
+        client: &Client,
+        timeout: Duration,
+    ) -> Vec<MirrorHealth> {
+        let mirrors = match mirror_type {
+            MirrorType::Search => &self.search_mirrors,
+            MirrorType::Download => &self.download_mirrors,
+        };
+        let mut health = join_all(
+            mirrors
+                .iter()
+                .map(|mirror| mirror.check_health(client, timeout)),
+        )
+        .await;
+        rank_health(&mut health);
+        health
+    }
+
+    /// Working mirrors sorted by latency. Blocked mirrors are reported but don't fail the call.
     pub async fn get_working_mirrors(
         &self,
         mirror_type: MirrorType,
         client: Arc<Client>,
-    ) -> Result<Vec<Mirror>, String> {
-        let mut working_mirrors = Vec::new();
-        let mut forbidden_mirrors = Vec::new();
-
-        if let MirrorType::Search = mirror_type {
-            for mirror in self.search_mirrors.iter() {
-                match mirror.check_connection(&client).await {
-                    Ok(_) => working_mirrors.push(mirror.clone()),
-                    Err(e) => {
-                        if e == StatusCode::FORBIDDEN {
-                            forbidden_mirrors.push(mirror.clone());
-                        }
-                        continue;
-                    }
-                };
-            }
-        } else {
-            for mirror in self.download_mirrors.iter() {
-                match mirror.check_connection(&client).await {
-                    Ok(_) => working_mirrors.push(mirror.clone()),
-                    Err(e) => {
-                        if e == StatusCode::FORBIDDEN {
-                            forbidden_mirrors.push(mirror.clone());
-                        }
-                        continue;
-                    }
-                };
-            }
-        }
-
-        if !forbidden_mirrors.is_empty() {
-            let forbidden_urls: Vec<String> = forbidden_mirrors
-                .iter()
-                .map(|mirror| mirror.host_url.to_string())
-                .collect();
-            Err(format!(
-                "The following mirrors were blocked: {}",
-                forbidden_urls.join(", ")
-            ))
-        } else if working_mirrors.is_empty() {
-            Err("Couldn't reach mirrors".to_string())
-        } else {
-            Ok(working_mirrors)
-        }
+    ) -> crate::Result<Vec<Mirror>> {
+        let health = self
+            .check_mirrors(mirror_type, &client, HEALTH_CHECK_TIMEOUT)
+            .await;
+        working_mirrors(&health)
     }
 
-    pub async fn spawn_get_working_mirrors_tasks(
-        self: Arc<Self>,
-        client: &Arc<Client>,
-    ) -> Vec<JoinHandle<Result<Vec<Mirror>, String>>> {
+    /// Starts checking the search and download mirrors in the background.
+    pub fn spawn_health_checks(self: Arc<Self>, client: &Arc<Client>) -> MirrorChecks {
         let search_mirrors_handle = tokio::spawn({
             let self_clone = Arc::clone(&self);
-            let client_clone = Arc::clone(&client);
+            let client_clone = Arc::clone(client);
             async move {
                 self_clone
-                    .get_working_mirrors(MirrorType::Search, client_clone)
+                    .check_mirrors(MirrorType::Search, &client_clone, HEALTH_CHECK_TIMEOUT)
                     .await
             }
         });
 
         let download_mirrors_handle = tokio::spawn({
             let self_clone = Arc::clone(&self);
-            let client_clone = Arc::clone(&client);
+            let client_clone = Arc::clone(client);
             async move {
                 self_clone
-                    .get_working_mirrors(MirrorType::Download, client_clone)
+                    .check_mirrors(MirrorType::Download, &client_clone, HEALTH_CHECK_TIMEOUT)
                     .await
             }
         });
 
-        vec![search_mirrors_handle, download_mirrors_handle]
+        MirrorChecks {
+            search: shared_check(search_mirrors_handle),
+            download: shared_check(download_mirrors_handle),
+        }
+    }
+}
+
+type SharedCheck = Shared<BoxFuture<'static, Vec<MirrorHealth>>>;
+
+fn shared_check(handle: JoinHandle<Vec<MirrorHealth>>) -> SharedCheck {
+    // NOTE:(akotro) A panicked check task just means no mirror could be checked
+    handle
+        .map(|health| health.unwrap_or_default())
+        .boxed()
+        .shared()
+}
+
+/// Health checks running in the background. Clones share the same checks, so they are only
+/// done once however many downloads wait on them.
+#[derive(Clone)]
+pub struct MirrorChecks {
+    search: SharedCheck,
+    download: SharedCheck,
+}
+
+impl MirrorChecks {
+    pub async fn health(&self, mirror_type: MirrorType) -> Vec<MirrorHealth> {
+        match mirror_type {
+            MirrorType::Search => self.search.clone().await,
+            MirrorType::Download => self.download.clone().await,
+        }
+    }
+}
+
+/// Picks the working mirrors out of ranked health checks, reporting the blocked ones.
+pub fn working_mirrors(health: &[MirrorHealth]) -> crate::Result<Vec<Mirror>> {
+    let blocked = health
+        .iter()
+        .filter(|h| h.blocked)
+        .map(|h| h.mirror.host_url.as_str())
+        .collect::<Vec<_>>();
+    if !blocked.is_empty() {
+        eprintln!("{}", Error::MirrorBlocked(blocked.join(", ")));
+    }
+
+    let working = health
+        .iter()
+        .filter(|h| h.is_working())
+        .map(|h| h.mirror.clone())
+        .collect::<Vec<_>>();
+    if working.is_empty() {
+        Err(Error::NoWorkingMirrors)
+    } else {
+        Ok(working)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::test_util::serve;
+
+    /// A mirror that answers every request with `status` and `body`.
+    async fn mirror_answering(status: &'static str, body: &'static str) -> Mirror {
+        let (host_url, _) = serve(status, body).await;
+        Mirror {
+            host_url,
+            ..Default::default()
+        }
+    }
+
+    async fn health(status: &'static str, body: &'static str) -> MirrorHealth {
+        mirror_answering(status, body)
+            .await
+            .check_health(&Client::new(), HEALTH_CHECK_TIMEOUT)
+            .await
+    }
+
+    #[tokio::test]
+    async fn success_is_working() {
+        let health = health("200 OK", "<html>Library Genesis</html>").await;
+        assert_eq!(health.status, Some(200));
+        assert!(health.reachable);
+        assert!(health.latency.is_some());
+        assert!(health.error.is_none());
+        assert!(health.is_working());
+    }
+
+    #[tokio::test]
+    async fn error_statuses_are_not_working() {
+        for (status, code) in [
+            ("403 Forbidden", 403),
+            ("404 Not Found", 404),
+            ("500 Internal Server Error", 500),
+            ("503 Service Unavailable", 503),
+        ] {
+            let health = health(status, "<html>Error</html>").await;
+            assert_eq!(health.status, Some(code));
+            assert!(health.reachable);
+            assert!(!health.blocked);
+            assert!(health.error.is_some(), "{}", status);
+            assert!(!health.is_working(), "{}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn block_pages_are_blocked() {
+        let health = health("403 Forbidden", "<title>Block Page</title>").await;
+        assert!(health.blocked);
+        assert!(!health.is_working());
+    }
+
+    #[tokio::test]
+    async fn unreachable_mirrors_are_not_working() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host_url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let mirror = Mirror {
+            host_url,
+            ..Default::default()
+        };
+        let health = mirror
+            .check_health(&Client::new(), HEALTH_CHECK_TIMEOUT)
+            .await;
+        assert!(!health.reachable);
+        assert_eq!(health.status, None);
+        assert!(health.error.is_some());
+        assert!(!health.is_working());
+    }
+
+    #[tokio::test]
+    async fn working_mirrors_skip_error_statuses() {
+        let client = Client::new();
+        let mut health = join_all([
+            mirror_answering("503 Service Unavailable", "")
+                .await
+                .check_health(&client, HEALTH_CHECK_TIMEOUT),
+            mirror_answering("200 OK", "ok")
+                .await
+                .check_health(&client, HEALTH_CHECK_TIMEOUT),
+        ])
+        .await;
+        rank_health(&mut health);
+
+        assert!(health[0].is_working());
+        assert_eq!(
+            working_mirrors(&health).unwrap(),
+            vec![health[0].mirror.clone()]
+        );
+        assert!(matches!(
+            working_mirrors(&health[1..]),
+            Err(Error::NoWorkingMirrors)
+        ));
     }
 }
@@ -1,5 +1,7 @@
 // NOTE: Credit to https://github.com/karasikq/libgen-rs.
 pub mod download;
+pub mod failover;
 pub mod mirrors;
 pub mod models;
+pub mod parse;
 pub mod search;
@@ -1,11 +1,18 @@
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
 pub struct LibgenBook {
     pub id: String,
     pub title: String,
     pub author: String,
+    /// The authors one by one, as the results page lists them. `author` joins them and can't
+    /// be split back, fiction names are written "Last, First"
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// Only known for fiction
+    #[serde(default)]
+    pub series: String,
     pub filesize: String,
     pub year: String,
     pub language: String,
@@ -15,17 +22,45 @@ pub struct LibgenBook {
     pub extension: String,
     pub md5: String,
     pub coverurl: String,
+    #[serde(default)]
+    pub topic: Topic,
+}
+
+/// The libgen collection a book is in, each has its own download pages.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    #[default]
+    NonFiction,
+    Fiction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadResult {
+    pub md5: String,
+    pub file_path: String,
+    pub mirror: String,
+}
+
+impl LibgenBook {
+    /// Url of the cover on the search mirror, `None` when libgen has none.
+    pub fn cover_url(&self) -> Option<&str> {
+        Some(self.coverurl.as_str()).filter(|url| url.starts_with("http"))
+    }
 }
 
 impl LibgenBook {
     #![allow(dead_code)]
-    fn print_libgen_book_info(&self) -> Result<(), &'static str> {
+    fn print_libgen_book_info(&self) -> crate::Result<()> {
         println!("ID: {}", self.id);
         println!("Title: {}", self.title);
         println!("Author: {}", self.author);
+        if !self.series.is_empty() {
+            println!("Series: {}", self.series);
+        }
         println!(
             "Filesize: {:.2} Mb",
-            self.filesize.parse::<u32>().unwrap() as f32 / 1048576.0
+            self.filesize.parse::<u64>().unwrap_or(0) as f64 / 1048576.0
         );
         println!("Year: {}", self.year);
         println!("Language: {}", self.language);
@@ -40,13 +75,14 @@ impl LibgenBook {
 
 impl fmt::Display for LibgenBook {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}, {}", self.title, self.extension, self.author)?;
+        if !self.series.is_empty() {
+            write!(f, " ({})", self.series)?;
+        }
         write!(
             f,
-            "{}.{}, {} = {}",
-            self.title,
-            self.extension,
-            self.author,
-            self.filesize.parse::<u32>().unwrap() as f32 / 1048576.0
+            " = {:.2} Mb",
+            self.filesize.parse::<u64>().unwrap_or(0) as f32 / 1048576.0
         )
     }
 }
@@ -0,0 +1,213 @@
+use lazy_static::lazy_static;
+use scraper::{ElementRef, Html, Selector};
+use serde::Serialize;
+
+use super::models::{LibgenBook, Topic};
+
+lazy_static! {
+    static ref NON_FICTION_ROW: Selector = Selector::parse("table.c tr").unwrap();
+    static ref FICTION_ROW: Selector = Selector::parse("table.catalog > tbody > tr").unwrap();
+    static ref CELL: Selector = Selector::parse("td").unwrap();
+    static ref LINK: Selector = Selector::parse("a").unwrap();
+    static ref AUTHOR: Selector = Selector::parse("ul.catalog_authors > li").unwrap();
+}
+
+/// A row of the non-fiction results table (`view=simple`).
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct SearchResultRow {
+    pub id: String,
+    pub authors: Vec<String>,
+    pub title: String,
+    pub series: String,
+    pub publisher: String,
+    pub year: String,
+    pub pages: String,
+    pub language: String,
+    /// As the page shows it, e.g. "5 Mb"
+    pub size: String,
+    pub extension: String,
+    pub md5: String,
+    pub mirror_links: Vec<String>,
+}
+
+impl From<SearchResultRow> for LibgenBook {
+    fn from(row: SearchResultRow) -> Self {
+        LibgenBook {
+            id: row.id,
+            title: row.title,
+            author: row.authors.join(", "),
+            authors: row.authors,
+            series: row.series,
+            filesize: parse_size(&row.size)
+                .map(|size| size.to_string())
+                .unwrap_or_default(),
+            year: row.year,
+            language: row.language,
+            pages: row.pages,
+            publisher: row.publisher,
+            extension: row.extension,
+            md5: row.md5,
+            ..Default::default()
+        }
+    }
+}
+
+/// Parses the results table of a non-fiction search page, in the order the page lists them.
+///
+/// The columns are id, author(s), title, publisher, year, pages, language, size, extension,
+/// the mirror links and an edit link. The md5 is taken from the title's `md5=` link, rows
+/// without one (the header) are skipped.
+pub fn parse_non_fiction_results(html: &str) -> Vec<SearchResultRow> {
+    let document = Html::parse_document(html);
+    document
+        .select(&NON_FICTION_ROW)
+        .filter_map(parse_non_fiction_row)
+        .collect()
+}
+
+fn parse_non_fiction_row(row: ElementRef) -> Option<SearchResultRow> {
+    let cells = row.select(&CELL).collect::<Vec<_>>();
+    if cells.len() < 9 {
+        return None;
+    }
+
+    let title_link = cells[2]
+        .select(&LINK)
+        .find(|link| non_fiction_md5(link).is_some())?;
+    let md5 = non_fiction_md5(&title_link)?;
+
+    // NOTE:(akotro) The title link also holds the isbns and edition in a <font>, only its
+    // own text is the title
+    let title = title_link
+        .children()
+        .filter_map(|node| node.value().as_text())
+        .flat_map(|text| text.split_whitespace())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let series = cells[2]
+        .select(&LINK)
+        .find(|link| {
+            link.value()
+                .attr("href")
+                .is_some_and(|href| href.contains("column=series"))
+        })
+        .map(text)
+        .unwrap_or_default();
+
+    let authors = cells[1]
+        .select(&LINK)
+        .map(text)
+        .filter(|author| !author.is_empty())
+        .collect::<Vec<_>>();
+
+    let mirror_links = cells[9..]
+        .iter()
+        .flat_map(|cell| cell.select(&LINK))
+        .filter(|link| !text(*link).eq_ignore_ascii_case("[edit]"))
+        .filter_map(|link| link.value().attr("href"))
+        .map(String::from)
+        .collect();
+
+    Some(SearchResultRow {
+        id: text(cells[0]),
+        authors,
+        title,
+        series,
+        publisher: text(cells[3]),
+        year: text(cells[4]),
+        pages: text(cells[5]),
+        language: text(cells[6]),
+        size: text(cells[7]),
+        extension: text(cells[8]).to_lowercase(),
+        md5,
+        mirror_links,
+    })
+}
+
+/// The md5 from a `book/index.php?md5=<md5>` link.
+fn non_fiction_md5(link: &ElementRef) -> Option<String> {
+    let href = link.value().attr("href")?;
+    let (_, md5) = href.split_once("md5=")?;
+    let md5 = md5.split('&').next()?;
+    is_md5(md5).then(|| md5.to_uppercase())
+}
+
+fn is_md5(hash: &str) -> bool {
+    hash.len() == 32 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Parses the results table of a fiction search page, in the order the page lists them.
+///
+/// The columns are author(s), series, title, language, file ("EPUB / 1.2 Mb") and mirrors,
+/// the md5 is taken from the title's `/fiction/<md5>` link.
+pub fn parse_fiction_results(html: &str) -> Vec<LibgenBook> {
+    let document = Html::parse_document(html);
+    document
+        .select(&FICTION_ROW)
+        .filter_map(parse_fiction_row)
+        .collect()
+}
+
+fn parse_fiction_row(row: ElementRef) -> Option<LibgenBook> {
+    let cells = row.select(&CELL).collect::<Vec<_>>();
+    if cells.len() < 5 {
+        return None;
+    }
+
+    let title_link = cells[2]
+        .select(&LINK)
+        .find(|link| fiction_md5(link).is_some())?;
+    let md5 = fiction_md5(&title_link)?;
+
+    let mut authors = cells[0].select(&AUTHOR).map(text).collect::<Vec<_>>();
+    if authors.is_empty() {
+        authors.push(text(cells[0]));
+    }
+
+    let file = text(cells[4]);
+    let (extension, size) = file.split_once('/').unwrap_or((file.as_str(), ""));
+
+    Some(LibgenBook {
+        title: text(title_link),
+        author: authors.join("; "),
+        authors,
+        series: text(cells[1]),
+        language: text(cells[3]),
+        extension: extension.trim().to_lowercase(),
+        filesize: parse_size(size)
+            .map(|size| size.to_string())
+            .unwrap_or_default(),
+        md5,
+        topic: Topic::Fiction,
+        ..Default::default()
+    })
+}
+
+/// The md5 from a `/fiction/<md5>` link, the mirror and edit links don't match.
+fn fiction_md5(link: &ElementRef) -> Option<String> {
+    let href = link.value().attr("href")?;
+    let md5 = href.trim_end_matches('/').rsplit('/').next()?;
+    (href.contains("/fiction/") && is_md5(md5)).then(|| md5.to_uppercase())
+}
+
+/// Element text with the whitespace, including `&nbsp;`, collapsed.
+fn text(element: ElementRef) -> String {
+    element
+        .text()
+        .flat_map(|text| text.split_whitespace())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Turns a size like "508 Kb" or "1.2 Mb" into bytes.
+fn parse_size(size: &str) -> Option<u64> {
+    let mut parts = size.split_whitespace();
+    let value = parts.next()?.replace(',', ".").parse::<f64>().ok()?;
+    let multiplier = match parts.next().map(|unit| unit.to_lowercase()).as_deref() {
+        Some("kb") => 1024.0,
+        Some("mb") => 1024.0 * 1024.0,
+        Some("gb") => 1024.0 * 1024.0 * 1024.0,
+        _ => 1.0,
+    };
+    Some((value * multiplier) as u64)
+}
@@ -1,13 +1,26 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-use crate::{libgen::mirrors::Mirror, libgen::models::LibgenBook, models::Book};
+use crate::{
+    error::Error,
+    libgen::{
+        mirrors::Mirror,
+        models::LibgenBook,
+        parse::{parse_fiction_results, parse_non_fiction_results},
+    },
+    models::Book,
+};
 use bytes::Bytes;
+use futures::{stream, StreamExt};
 use lazy_static::lazy_static;
-use regex::bytes::Regex;
 use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+
+/// How many hashes are looked up with a single `json.php` request
+const HASHES_PER_LOOKUP: usize = 25;
+/// How many lookups run at the same time
+const CONCURRENT_LOOKUPS: usize = 4;
 
 lazy_static! {
-    static ref HASH_REGEX: Regex = Regex::new(r"[A-Z0-9]{32}").unwrap();
     static ref JSON_QUERY: String =
         "id,title,author,filesize,extension,md5,year,language,pages,publisher,edition,coverurl"
             .to_string();
@@ -27,8 +40,94 @@ impl std::fmt::Display for SearchType {
     }
 }
 
-pub fn create_non_fiction_search_query(search_url: String, book: &Book) -> String {
-    let mut search_url = Url::parse(&search_url).unwrap();
+/// Filters and preferences applied to libgen searches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchOptions {
+    /// Languages to keep, e.g. "English". Any language when empty
+    pub languages: Vec<String>,
+    /// Extensions in order of preference, e.g. "epub" before "pdf".
+    /// Results with other extensions are kept, but listed last
+    pub extensions: Vec<String>,
+    pub year_from: Option<u32>,
+    pub year_to: Option<u32>,
+    /// Largest file to keep, in bytes
+    pub max_size: Option<u64>,
+    /// Results per search page, libgen accepts 25, 50 or 100
+    pub results: u32,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            languages: vec![String::from("English")],
+            extensions: vec![],
+            year_from: None,
+            year_to: None,
+            max_size: None,
+            results: 25,
+        }
+    }
+}
+
+impl SearchOptions {
+    /// Whether the book passes the filters. Fields a mirror didn't fill in don't count against it.
+    pub fn matches(&self, book: &LibgenBook) -> bool {
+        let language_matches = book.language.is_empty()
+            || self.languages.is_empty()
+            || self
+                .languages
+                .iter()
+                .any(|language| language.eq_ignore_ascii_case(&book.language));
+
+        let year = book.year.trim().parse::<u32>().ok();
+        let year_matches = match year {
+            Some(year) => {
+                self.year_from.into_iter().all(|from| year >= from)
+                    && self.year_to.into_iter().all(|to| year <= to)
+            }
+            None => true,
+        };
+
+        let size_matches = match (self.max_size, book.filesize.parse::<u64>()) {
+            (Some(max_size), Ok(size)) => size <= max_size,
+            _ => true,
+        };
+
+        language_matches && year_matches && size_matches
+    }
+
+    /// Drops the books that don't match and moves the preferred extensions to the front,
+    /// keeping the search page order otherwise.
+    pub fn apply(&self, books: Vec<LibgenBook>) -> Vec<LibgenBook> {
+        let mut books = books
+            .into_iter()
+            .filter(|book| self.matches(book))
+            .collect::<Vec<_>>();
+        books.sort_by_key(|book| {
+            self.extensions
+                .iter()
+                .position(|extension| extension.eq_ignore_ascii_case(&book.extension))
+                .unwrap_or(self.extensions.len())
+        });
+        books
+    }
+
+    /// The language a fiction search can be narrowed down to, it only takes one.
+    fn fiction_language(&self) -> &str {
+        match self.languages.as_slice() {
+            [language] => language,
+            _ => "",
+        }
+    }
+}
+
+pub fn create_non_fiction_search_query(
+    search_url: String,
+    book: &Book,
+    options: &SearchOptions,
+) -> crate::Result<String> {
+    let mut search_url = Url::parse(&search_url)?;
     let mut search_query = search_url.query_pairs_mut();
 
     search_query
@@ -46,15 +145,19 @@ pub fn create_non_fiction_search_query(search_url: String, book: &Book) -> Strin
             .as_str(),
         )
         .append_pair("lg_topic", "libgen")
-        .append_pair("res", "25")
+        .append_pair("res", &options.results.to_string())
         .append_pair("open", "0")
         .append_pair("view", "simple")
         .append_pair("phrase", "1");
-    search_query.finish().to_string()
+    Ok(search_query.finish().to_string())
 }
 
-pub fn create_fiction_search_query(search_url: String, book: &Book) -> String {
-    let mut search_url = Url::parse(&search_url).unwrap();
+pub fn create_fiction_search_query(
+    search_url: String,
+    book: &Book,
+    options: &SearchOptions,
+) -> crate::Result<String> {
+    let mut search_url = Url::parse(&search_url)?;
     let mut search_query = search_url.query_pairs_mut();
 
     search_query
@@ -72,126 +175,201 @@ pub fn create_fiction_search_query(search_url: String, book: &Book) -> String {
             .as_str(),
         )
         .append_pair("criteria", "")
-        .append_pair("language", "English")
+        .append_pair("language", options.fiction_language())
         .append_pair("format", "");
-    search_query.finish().to_string()
+    Ok(search_query.finish().to_string())
 }
 
 pub async fn search_non_fiction(
     book: &Book,
     mirror: &Mirror,
     client: &Client,
-) -> Result<Vec<LibgenBook>, &'static str> {
-    let search_url = mirror.search_url.clone().unwrap();
-    let search_url = create_non_fiction_search_query(search_url, book);
-
-    let content = match get_content(search_url, client).await {
-        Ok(b) => b,
-        Err(e) => {
-            println!("Error: {:?}", e);
-            return Err("Failed to get content from page");
-        }
-    };
+    options: &SearchOptions,
+) -> crate::Result<Vec<LibgenBook>> {
+    let search_url = mirror
+        .search_url
+        .clone()
+        .ok_or_else(|| Error::UnsupportedMirror(mirror.host_url.clone()))?;
+    let search_url = create_non_fiction_search_query(search_url, book, options)?;
 
-    let book_hashes = parse_hashes(content);
-    Ok(get_books(&book_hashes, &mirror, client).await)
+    search_non_fiction_with_query(search_url, mirror, client, options).await
 }
 
 pub async fn search_non_fiction_with_query(
     search_query: String,
     mirror: &Mirror,
     client: &Client,
-) -> Result<Vec<LibgenBook>, &'static str> {
-    let content = match get_content(search_query, client).await {
-        Ok(b) => b,
-        Err(e) => {
-            println!("Error: {:?}", e);
-            return Err("Failed to get content from page");
+    options: &SearchOptions,
+) -> crate::Result<Vec<LibgenBook>> {
+    let content = get_content(search_query, mirror, client).await?;
+
+    let rows = parse_non_fiction_results(&String::from_utf8_lossy(&content));
+    let book_hashes = rows.iter().map(|row| row.md5.clone()).collect::<Vec<_>>();
+    let books = match get_books(&book_hashes, mirror, client).await {
+        Ok(mut books) if !books.is_empty() || rows.is_empty() => {
+            // NOTE:(akotro) The json has the authors in one string, the page lists them
+            let mut authors_by_md5 = rows
+                .into_iter()
+                .map(|row| (row.md5.to_uppercase(), row.authors))
+                .collect::<HashMap<_, _>>();
+            for book in &mut books {
+                if let Some(authors) = authors_by_md5.remove(&book.md5.to_uppercase()) {
+                    book.authors = authors;
+                }
+            }
+            books
+        }
+        // NOTE:(akotro) The results page has most of the metadata too, only the covers are missing
+        lookup => {
+            if let Err(e) = lookup {
+                eprintln!("Using the results page instead: {}", e);
+            }
+            let mut unique_hashes = HashSet::new();
+            rows.into_iter()
+                .filter(|row| unique_hashes.insert(row.md5.clone()))
+                .map(LibgenBook::from)
+                .collect()
         }
     };
-
-    let book_hashes = parse_hashes(content);
-    Ok(get_books(&book_hashes, &mirror, client).await)
+    Ok(options.apply(books))
 }
 
 pub async fn search_fiction(
     book: &Book,
     mirror: &Mirror,
     client: &Client,
-) -> Result<String, &'static str> {
-    let search_url = mirror.search_url_fiction.clone().unwrap();
-    let search_url = create_fiction_search_query(search_url, book);
-
-    let content = match get_content(search_url, client).await {
-        Ok(b) => b,
-        Err(e) => {
-            println!("Error: {:?}", e);
-            return Err("Failed to get content from page");
-        }
-    };
+    options: &SearchOptions,
+) -> crate::Result<Vec<LibgenBook>> {
+    let search_url = mirror
+        .search_url_fiction
+        .clone()
+        .ok_or_else(|| Error::UnsupportedMirror(mirror.host_url.clone()))?;
+    let search_url = create_fiction_search_query(search_url, book, options)?;
 
-    let book_hashes = parse_hashes(content);
-    match book_hashes.first() {
-        Some(h) => Ok(h.to_owned()),
-        None => Err("No hash found"),
-    }
-}
+    let content = get_content(search_url, mirror, client).await?;
 
-async fn get_content(url: String, client: &Client) -> Result<Bytes, reqwest::Error> {
-    println!("Getting content from: {}", url.as_str());
-    client.get(url).send().await?.bytes().await
+    let mut books = options.apply(parse_fiction_results(&String::from_utf8_lossy(&content)));
+    books.truncate(options.results as usize);
+    Ok(books)
 }
 
-fn parse_hashes(content: Bytes) -> Vec<String> {
-    // println!("{}", std::str::from_utf8(content.as_ref()).unwrap().to_string());
-    let mut hashes: Vec<String> = Vec::new();
-    for caps in HASH_REGEX.captures_iter(&content) {
-        let capture = match caps.get(0) {
-            Some(c) => c,
-            None => continue,
-        };
-        hashes.push(std::str::from_utf8(capture.as_bytes()).unwrap().to_string());
+/// The page at `url` on `mirror`, error statuses and block pages fail so the next mirror
+/// is tried.
+async fn get_content(url: String, mirror: &Mirror, client: &Client) -> crate::Result<Bytes> {
+    eprintln!("Getting content from: {}", url.as_str());
+    let response = client.get(url).send().await?;
+    // NOTE:(akotro) Block pages usually come with a 403, they are reported as blocked
+    let status_error = response.error_for_status_ref().err();
+    let content = response.bytes().await?;
+    if String::from_utf8_lossy(&content).contains("Block Page") {
+        return Err(Error::MirrorBlocked(mirror.host_url.clone()));
+    }
+    match status_error {
+        Some(e) => Err(e.into()),
+        None => Ok(content),
     }
-
-    let mut unique_hashes = HashSet::new();
-
-    hashes
-        .into_iter()
-        .filter(|x| unique_hashes.insert(x.clone()))
-        .collect()
 }
 
-async fn get_books(hashes: &[String], mirror: &Mirror, client: &Client) -> Vec<LibgenBook> {
-    let mut parsed_books: Vec<LibgenBook> = Vec::new();
-    let cover_url = String::from(mirror.cover_pattern.as_ref().unwrap());
+/// Looks the hashes up in batches, a few batches at a time. The books come back in the order
+/// of `hashes`, once per md5.
+async fn get_books(
+    hashes: &[String],
+    mirror: &Mirror,
+    client: &Client,
+) -> crate::Result<Vec<LibgenBook>> {
+    let sync_url = mirror
+        .sync_url
+        .as_ref()
+        .ok_or_else(|| Error::UnsupportedMirror(mirror.host_url.clone()))?;
 
-    for hash in hashes.iter() {
-        // println!("hash: {}", hash);
-        let mut search_url =
-            Url::parse(mirror.sync_url.as_ref().expect("Expected an Url").as_str()).unwrap();
-        search_url
+    let mut lookup_urls = Vec::new();
+    for chunk in hashes.chunks(HASHES_PER_LOOKUP) {
+        let mut lookup_url = Url::parse(sync_url)?;
+        lookup_url
             .query_pairs_mut()
-            .append_pair("ids", hash)
+            .append_pair("ids", &chunk.join(","))
             .append_pair("fields", &JSON_QUERY);
-        let content = match get_content(search_url.to_string(), client).await {
-            Ok(v) => v,
-            Err(_) => continue,
-        };
-        let mut libgen_books: Vec<LibgenBook> =
-            match serde_json::from_str(std::str::from_utf8(&content).unwrap()) {
-                Ok(v) => v,
-                Err(_) => {
-                    println!("Couldn't parse json");
-                    continue;
+        lookup_urls.push(lookup_url.to_string());
+    }
+
+    let lookups = stream::iter(lookup_urls)
+        .map(|lookup_url| get_libgen_books(lookup_url, mirror, client))
+        .buffered(CONCURRENT_LOOKUPS)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut books_by_md5: HashMap<String, LibgenBook> = HashMap::new();
+    let mut first_error = None;
+    for lookup in lookups {
+        match lookup {
+            Ok(books) => {
+                for book in books {
+                    books_by_md5.entry(book.md5.to_uppercase()).or_insert(book);
                 }
-            };
-        libgen_books.retain(|b| b.language == "English");
-        libgen_books.iter_mut().for_each(|b| {
-            if mirror.cover_pattern.is_some() {
-                b.coverurl = cover_url.replace("{cover-url}", &b.coverurl);
             }
-        });
-        parsed_books.append(&mut libgen_books);
+            Err(e) => {
+                eprintln!("Couldn't look up books: {}", e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    // NOTE:(akotro) Only give up on the mirror when none of the lookups worked
+    if let (true, Some(e)) = (books_by_md5.is_empty(), first_error) {
+        return Err(e);
+    }
+
+    Ok(hashes
+        .iter()
+        .filter_map(|hash| books_by_md5.remove(&hash.to_uppercase()))
+        .map(|mut book| {
+            match &mirror.cover_pattern {
+                Some(cover_pattern) if !book.coverurl.is_empty() => {
+                    book.coverurl = cover_pattern.replace("{cover-url}", &book.coverurl);
+                }
+                _ => {}
+            }
+            book
+        })
+        .collect())
+}
+
+async fn get_libgen_books(
+    lookup_url: String,
+    mirror: &Mirror,
+    client: &Client,
+) -> crate::Result<Vec<LibgenBook>> {
+    let content = get_content(lookup_url, mirror, client).await?;
+    Ok(serde_json::from_slice(&content)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(year: &str) -> LibgenBook {
+        LibgenBook {
+            year: year.to_string(),
+            language: String::from("English"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn year_bounds_are_inclusive_and_optional() {
+        let mut options = SearchOptions {
+            year_from: Some(2000),
+            year_to: Some(2010),
+            ..Default::default()
+        };
+        assert!(options.matches(&book("2000")));
+        assert!(options.matches(&book("2010")));
+        assert!(!options.matches(&book("1999")));
+        assert!(!options.matches(&book("2011")));
+        assert!(options.matches(&book("")));
+
+        options.year_to = None;
+        assert!(options.matches(&book("2023")));
+        options.year_from = None;
+        assert!(options.matches(&book("1850")));
     }
-    parsed_books
 }
@@ -1,7 +1,7 @@
 use std::{
-    fs::{read, File},
-    io::{stdout, Write},
-    str::from_utf8,
+    fs::{self, read_to_string, File, OpenOptions},
+    io::{self, stderr, Write},
+    path::Path,
     sync::{
         mpsc::{self, Sender},
         Arc,
@@ -10,84 +10,289 @@ use std::{
     time::Duration,
 };
 
-use inquire::Select;
-use reqwest::{header::CONTENT_DISPOSITION, Client, Response};
-use tokio::task::JoinHandle;
+use futures::{stream, StreamExt};
+use inquire::{InquireError, Select};
+use md5::{Digest, Md5};
+use reqwest::{
+    header::{CONTENT_DISPOSITION, CONTENT_RANGE, RANGE},
+    Client, Response, StatusCode, Url,
+};
 
 use crate::{
+    covers::CoverCache,
+    epub,
+    error::Error,
     libgen::{
-        download::{download_book, download_book_fiction},
-        mirrors::{Mirror, MirrorList, MirrorType},
-        models::LibgenBook,
-        search::{search_fiction, search_non_fiction, SearchType},
+        download::resolve_download_url,
+        failover::{
+            search_fiction_failover, search_non_fiction_failover, try_mirrors, with_preferred,
+        },
+        mirrors::{working_mirrors, Mirror, MirrorChecks, MirrorList, MirrorType},
+        models::{DownloadResult, LibgenBook},
+        search::{SearchOptions, SearchType},
     },
-    models::Book,
+    library::{DownloadTarget, LibraryOptions},
+    models::{Book, VolumeInfo},
 };
 
-pub async fn libgen_book_download(
-    book: Book,
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 3;
+/// How many books of a batch are downloaded at the same time
+const BATCH_DOWNLOAD_CONCURRENCY: usize = 3;
+
+/// How download progress is written to stderr.
+#[derive(Debug, Clone, Copy)]
+pub enum Progress<'a> {
+    /// A spinner and a percentage updated in place, for a single download
+    Inline,
+    /// A line every 10% prefixed with a label, so concurrent downloads stay readable
+    Labeled(&'a str),
+}
+
+impl Progress<'_> {
+    fn message(&self, message: &str) {
+        match self {
+            Progress::Inline => eprintln!("{}", message),
+            Progress::Labeled(label) => eprintln!("[{}] {}", label, message),
+        }
+    }
+
+    fn update(&self, downloaded: u64, total_size: Option<u64>, last_step: &mut u64) {
+        let total_size = match total_size {
+            Some(total_size) if total_size > 0 => total_size,
+            _ => return,
+        };
+        let percent = (downloaded as f64 / total_size as f64) * 100.0;
+        match self {
+            Progress::Inline => eprint!("\r   Progress -- {:.2}%", percent),
+            Progress::Labeled(label) => {
+                let step = percent as u64 / 10;
+                if step > *last_step {
+                    *last_step = step;
+                    eprintln!("[{}] {}%", label, step * 10);
+                }
+            }
+        }
+    }
+}
+
+pub type BatchResult = Vec<(Book, crate::Result<DownloadResult>)>;
+
+/// Looks up each book on libgen and downloads them, a few at a time.
+/// Returns how every book went, a failed book doesn't stop the others.
+///
+/// The metadata of downloaded epubs is rewritten, with `covers` their covers are embedded too.
+pub async fn libgen_batch_download(
+    books: Vec<Book>,
     client: &Arc<Client>,
-    mirror_handles: &mut Vec<JoinHandle<Result<Vec<Mirror>, String>>>,
-) -> Result<(), String> {
-    let search_mirrors_handle = mirror_handles.remove(0);
-    let download_mirrors_handle = mirror_handles.remove(0);
-
-    println!("Getting working search mirrors...");
-    let working_search_mirrors = match search_mirrors_handle.await.unwrap() {
-        Ok(search_mirrors) => search_mirrors,
-        Err(e) => return Err(e),
-    };
+    mirror_checks: &MirrorChecks,
+    options: &SearchOptions,
+    library: &LibraryOptions,
+    mut covers: Option<&mut CoverCache>,
+) -> crate::Result<BatchResult> {
+    let (working_search_mirrors, working_download_mirrors) =
+        await_working_mirrors(mirror_checks).await?;
 
-    println!("Getting working download mirrors...");
-    let working_download_mirrors = match download_mirrors_handle.await.unwrap() {
-        Ok(search_mirrors) => search_mirrors,
-        Err(e) => return Err(e),
-    };
+    let selected_search_type = select_search_type()?;
 
-    let selected_search_type = select_search_type();
+    let search_mirror = select_mirror(MirrorType::Search, &working_search_mirrors)?;
+    let search_mirrors = with_preferred(&working_search_mirrors, &search_mirror);
+    let download_mirror = select_mirror(MirrorType::Download, &working_download_mirrors)?;
+    let download_mirrors = with_preferred(&working_download_mirrors, &download_mirror);
 
-    match selected_search_type {
-        SearchType::NonFiction => {
-            let search_mirror = select_mirror(MirrorType::Search, &working_search_mirrors);
+    // NOTE:(akotro) Pick every file up front, prompts can't run alongside the downloads
+    let mut results = BatchResult::new();
+    let mut downloads = Vec::new();
+    for book in books {
+        eprintln!("\nSearching for {}", book_label(&book));
+        match find_libgen_book(
+            &book,
+            &selected_search_type,
+            &search_mirrors,
+            client,
+            options,
+        )
+        .await
+        {
+            Ok(libgen_book) => downloads.push((book, libgen_book)),
+            Err(e @ Error::Prompt(InquireError::OperationInterrupted)) => return Err(e),
+            Err(e) => results.push((book, Err(e))),
+        }
+    }
 
-            let books = search_non_fiction(&book, &search_mirror, &client).await?;
-            if books.is_empty() {
-                return Err(String::from("No books were found"));
+    let single_download = downloads.len() == 1;
+    let download_results = stream::iter(downloads)
+        .map(|(book, libgen_book)| {
+            let download_mirrors = &download_mirrors;
+            async move {
+                let label = book_label(&book);
+                let progress = if single_download {
+                    Progress::Inline
+                } else {
+                    Progress::Labeled(&label)
+                };
+                let target = DownloadTarget {
+                    libgen_book: &libgen_book,
+                    book: Some(&book),
+                    library,
+                };
+                let result = download_failover(client, download_mirrors, target, progress).await;
+                (book, libgen_book, result)
             }
+        })
+        .buffer_unordered(BATCH_DOWNLOAD_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    // NOTE:(akotro) The cache isn't shared between the downloads, covers are fetched after
+    for (book, libgen_book, result) in download_results {
+        if let Ok(download) = &result {
+            let cover = match covers.as_deref_mut() {
+                Some(cache) if epub::is_epub(Path::new(&download.file_path)) => {
+                    cache
+                        .fetch_first(client, cover_urls(&libgen_book, &book))
+                        .await
+                }
+                _ => None,
+            };
+            embed_metadata(download, &book.volume_info, cover.as_deref());
+        }
+        results.push((book, result));
+    }
 
-            let selected_book = select_libgen_book(&books, "Select a book to download");
+    Ok(results)
+}
 
-            let download_mirror = select_mirror(MirrorType::Download, &working_download_mirrors);
+/// Where a downloaded book's cover can be found, best first.
+/// Libgen's covers are larger than the Google Books thumbnails.
+pub fn cover_urls<'a>(libgen_book: &'a LibgenBook, book: &'a Book) -> Vec<&'a str> {
+    libgen_book
+        .cover_url()
+        .into_iter()
+        .chain(book.volume_info.cover_url())
+        .collect()
+}
 
-            let download_response =
-                download_book(&client, &download_mirror, &selected_book).await?;
-            write_response_to_file(download_response)
-                .await
-                .expect("Failed to save file");
+async fn find_libgen_book(
+    book: &Book,
+    search_type: &SearchType,
+    search_mirrors: &[Mirror],
+    client: &Client,
+    options: &SearchOptions,
+) -> crate::Result<LibgenBook> {
+    let (books, _) = match search_type {
+        SearchType::NonFiction => {
+            search_non_fiction_failover(book, search_mirrors, client, options).await?
         }
         SearchType::Fiction => {
-            println!("\n*****************************************************");
-            println!("NOTE: Fiction books are not fully supported yet");
-            println!("The first book found will be downloaded automatically");
-            println!("*****************************************************\n");
-            let search_mirror = select_mirror(MirrorType::Search, &working_search_mirrors);
-
-            let book_hash = search_fiction(&book, &search_mirror, &client).await?;
-            if book_hash.is_empty() {
-                return Err(String::from("No books were found"));
-            }
+            search_fiction_failover(book, search_mirrors, client, options).await?
+        }
+    };
+    if books.is_empty() {
+        return Err(Error::NotFound("No books were found".to_string()));
+    }
 
-            let download_mirror = select_mirror(MirrorType::Download, &working_download_mirrors);
+    let prompt = format!("Select a book to download for {}:", book_label(book));
+    select_libgen_book(&books, &prompt)
+}
 
-            let download_response =
-                download_book_fiction(&client, &download_mirror, &book_hash).await?;
-            write_response_to_file(download_response)
-                .await
-                .expect("Faled to save file");
+/// Writes the book's metadata, and the cover if given, into a downloaded epub. Other formats
+/// are left as they are. The download itself succeeded, so a failure is only reported.
+pub fn embed_metadata(result: &DownloadResult, volume_info: &VolumeInfo, cover: Option<&Path>) {
+    let path = Path::new(&result.file_path);
+    if !epub::is_epub(path) {
+        return;
+    }
+    if let Err(e) = epub::write_metadata(path, volume_info, cover) {
+        eprintln!("Couldn't write the metadata of {}: {}", result.file_path, e);
+    }
+}
+
+pub fn book_label(book: &Book) -> String {
+    book.volume_info
+        .title
+        .clone()
+        .unwrap_or_else(|| book.id.clone())
+}
+
+/// Waits for the background health checks, they can be awaited any number of times.
+pub async fn await_working_mirrors(
+    mirror_checks: &MirrorChecks,
+) -> crate::Result<(Vec<Mirror>, Vec<Mirror>)> {
+    eprintln!("Getting working search mirrors...");
+    let working_search_mirrors = working_mirrors(&mirror_checks.health(MirrorType::Search).await)?;
+
+    eprintln!("Getting working download mirrors...");
+    let working_download_mirrors =
+        working_mirrors(&mirror_checks.health(MirrorType::Download).await)?;
+
+    Ok((working_search_mirrors, working_download_mirrors))
+}
+
+pub async fn download_libgen_book(
+    client: &Client,
+    mirror: &Mirror,
+    target: DownloadTarget<'_>,
+    progress: Progress<'_>,
+) -> crate::Result<DownloadResult> {
+    let download_url = resolve_download_url(client, mirror, target.libgen_book).await?;
+    let file_path = download_to_file(client, &download_url, target, progress).await?;
+
+    Ok(DownloadResult {
+        md5: target.md5().to_string(),
+        file_path,
+        mirror: mirror.host_url.clone(),
+    })
+}
+
+/// Downloads the book from the first of the ranked mirrors that serves it intact,
+/// the result tells which one that was.
+pub async fn download_failover(
+    client: &Client,
+    mirrors: &[Mirror],
+    target: DownloadTarget<'_>,
+    progress: Progress<'_>,
+) -> crate::Result<DownloadResult> {
+    let (result, _) = try_mirrors(mirrors, |mirror| {
+        download_libgen_book(client, mirror, target, progress)
+    })
+    .await?;
+    Ok(result)
+}
+
+/// Downloads into `<md5>.part` in the library directory, resuming it when the connection
+/// drops. The file is only moved into place once its contents hash to the book's md5.
+async fn download_to_file(
+    client: &Client,
+    url: &Url,
+    target: DownloadTarget<'_>,
+    progress: Progress<'_>,
+) -> crate::Result<String> {
+    let part_path = target.part_path();
+    if let Some(dir) = part_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut attempt = 1;
+    let mirror_filename = loop {
+        match write_response_to_file(client, url, &part_path, target.md5(), progress).await {
+            Err(e @ (Error::Network(_) | Error::IncompleteDownload { .. }))
+                if attempt < MAX_DOWNLOAD_ATTEMPTS =>
+            {
+                progress.message(&format!("{}, resuming download...", e));
+                attempt += 1;
+            }
+            result => break result?,
         }
+    };
+
+    let file_path = target.file_path(mirror_filename.or_else(|| url_filename(url)).as_deref());
+    if let Some(dir) = file_path.parent() {
+        fs::create_dir_all(dir)?;
     }
+    fs::rename(&part_path, &file_path)?;
+    progress.message("Done!");
 
-    Ok(())
+    Ok(file_path.display().to_string())
 }
 
 fn start_loading_spinner() -> Sender<()> {
@@ -99,8 +304,8 @@ fn start_loading_spinner() -> Sender<()> {
             if rx.try_recv().is_ok() {
                 break;
             }
-            print!("\r{}", spinner.chars().nth(i).unwrap());
-            stdout().flush().unwrap();
+            eprint!("\r{}", spinner.chars().nth(i).unwrap());
+            stderr().flush().ok();
             i = (i + 1) % spinner.len();
             thread::sleep(Duration::from_millis(100));
         }
@@ -108,86 +313,355 @@ fn start_loading_spinner() -> Sender<()> {
     tx
 }
 
+/// Writes the response to the part file and checks it, returns the filename the mirror sent.
 async fn write_response_to_file(
-    mut response: Response,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let total_size = response.content_length().unwrap_or(0);
-    let mut downloaded: u64 = 0;
+    client: &Client,
+    url: &Url,
+    part_path: &Path,
+    md5: &str,
+    progress: Progress<'_>,
+) -> crate::Result<Option<String>> {
+    let resume_from = fs::metadata(part_path).map(|m| m.len()).unwrap_or(0);
 
-    let headers = response.headers();
-    let mut file_path = String::new();
-    if let Some(content_disposition) = headers.get(CONTENT_DISPOSITION) {
-        let content_disposition_str = content_disposition.to_str().unwrap();
-        println!("{}", content_disposition_str);
+    let mut request = client.get(url.clone());
+    if resume_from > 0 {
+        request = request.header(RANGE, format!("bytes={}-", resume_from));
+    }
+    let mut response = request.send().await?;
 
-        if let Some(filename) = parse_filename(content_disposition_str) {
-            file_path = filename;
+    let (mut file, mut downloaded) = match response.status() {
+        StatusCode::PARTIAL_CONTENT if resume_from > 0 => {
+            progress.message(&format!("Resuming download from {} bytes", resume_from));
+            (
+                OpenOptions::new().append(true).open(part_path)?,
+                resume_from,
+            )
         }
+        // NOTE:(akotro) The part file doesn't match what the mirror has, start over
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            response = client.get(url.clone()).send().await?.error_for_status()?;
+            (File::create(part_path)?, 0)
+        }
+        // NOTE:(akotro) Mirrors that don't support ranges send the whole file again
+        _ => {
+            response = response.error_for_status()?;
+            (File::create(part_path)?, 0)
+        }
+    };
+    let total_size = total_size(&response, downloaded);
+
+    // NOTE:(akotro) Bytes kept from an earlier attempt are part of the checksum too
+    let mut hasher = Md5::new();
+    if downloaded > 0 {
+        io::copy(&mut File::open(part_path)?, &mut hasher)?;
     }
-    let mut file = File::create(file_path)?;
 
-    let tx = start_loading_spinner();
+    // NOTE:(akotro) Some mirrors send the filename as raw utf-8 instead of using `filename*`
+    let mirror_filename = response
+        .headers()
+        .get(CONTENT_DISPOSITION)
+        .map(|content_disposition| String::from_utf8_lossy(content_disposition.as_bytes()))
+        .and_then(|content_disposition| {
+            progress.message(&content_disposition);
+            parse_filename(&content_disposition)
+        });
 
-    while let Some(chunk) = response.chunk().await? {
-        file.write_all(&chunk)?;
-        downloaded += chunk.len() as u64;
-        print!(
-            "\r   Progress -- {:.2}%",
-            (downloaded as f64 / total_size as f64) * 100.0
-        );
+    let tx = match progress {
+        Progress::Inline => Some(start_loading_spinner()),
+        Progress::Labeled(_) => None,
+    };
+    let mut last_step = downloaded * 10 / total_size.unwrap_or(u64::MAX).max(1);
+
+    let result = async {
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk)?;
+            hasher.update(&chunk);
+            downloaded += chunk.len() as u64;
+            progress.update(downloaded, total_size, &mut last_step);
+        }
+        file.flush()?;
+        Ok::<(), Error>(())
     }
+    .await;
+    if let Some(tx) = tx {
+        tx.send(()).ok();
+    }
+    result?;
 
-    println!("\nDone!");
-    tx.send(()).unwrap();
+    if let Some(total_size) = total_size {
+        if downloaded < total_size {
+            return Err(Error::IncompleteDownload {
+                received: downloaded,
+                expected: total_size,
+            });
+        }
+    }
 
-    Ok(())
+    let actual_md5 = hex::encode(hasher.finalize());
+    if !actual_md5.eq_ignore_ascii_case(md5) {
+        fs::remove_file(part_path)?;
+        return Err(Error::ChecksumMismatch {
+            expected: md5.to_lowercase(),
+            actual: actual_md5,
+        });
+    }
+
+    if let Progress::Inline = progress {
+        eprintln!();
+    }
+
+    Ok(mirror_filename)
 }
 
+/// Size of the whole file, taken from `Content-Range` when resuming.
+fn total_size(response: &Response, offset: u64) -> Option<u64> {
+    response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.rsplit('/').next())
+        .and_then(|total| total.parse().ok())
+        .or_else(|| response.content_length().map(|length| offset + length))
+}
+
+/// The filename of a `Content-Disposition` header. The RFC 5987 `filename*` parameter is
+/// preferred over `filename`, like browsers do.
 fn parse_filename(content_disposition: &str) -> Option<String> {
-    let parts: Vec<&str> = content_disposition.split(';').collect();
-    for part in parts {
-        let part = part.trim();
-        if part.starts_with("filename=") {
-            let filename = part[9..].trim_matches('"');
-            return Some(filename.to_string());
+    let mut filename = None;
+    for parameter in split_parameters(content_disposition).into_iter().skip(1) {
+        let Some((name, value)) = parameter.split_once('=') else {
+            continue;
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                if let Some(extended_filename) = decode_extended_value(value.trim()) {
+                    return Some(extended_filename);
+                }
+            }
+            "filename" => filename = Some(unquote(value.trim())),
+            _ => {}
+        }
+    }
+    filename.filter(|filename| !filename.is_empty())
+}
+
+/// Splits a header on the `;` that aren't inside a quoted string.
+fn split_parameters(header: &str) -> Vec<&str> {
+    let mut parameters = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (index, c) in header.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                parameters.push(&header[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    parameters.push(&header[start..]);
+    parameters
+}
+
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(quoted) => {
+            let mut unquoted = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
         }
+        None => value.to_string(),
     }
-    None
 }
 
-pub fn parse_mirrors() -> MirrorList {
+/// Decodes an RFC 5987 value, `charset'language'percent-encoded`.
+fn decode_extended_value(value: &str) -> Option<String> {
+    let value = unquote(value);
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?.as_bytes();
+
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut index = 0;
+    while index < encoded.len() {
+        let decoded = match encoded[index] {
+            b'%' => encoded
+                .get(index + 1..index + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match decoded {
+            Some(byte) => {
+                bytes.push(byte);
+                index += 3;
+            }
+            None => {
+                bytes.push(encoded[index]);
+                index += 1;
+            }
+        }
+    }
+
+    let filename = if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes).ok()?
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        bytes.into_iter().map(char::from).collect()
+    } else {
+        return None;
+    };
+    Some(filename).filter(|filename| !filename.is_empty())
+}
+
+/// The filename in a download url, ipfs gateways take it as a `filename` query parameter.
+/// Urls with a query are scripts like `get.php`, only the others end in the filename.
+fn url_filename(url: &Url) -> Option<String> {
+    url.query_pairs()
+        .find(|(name, _)| name == "filename")
+        .map(|(_, filename)| filename.into_owned())
+        .or_else(|| {
+            if url.query().is_some() {
+                return None;
+            }
+            url.path_segments()?
+                .next_back()
+                .filter(|segment| segment.contains('.'))
+                .and_then(|segment| decode_extended_value(&format!("utf-8''{}", segment)))
+        })
+}
+
+pub fn parse_mirrors() -> crate::Result<MirrorList> {
     let mirror_path = if cfg!(debug_assertions) {
         "resources/mirrors.json"
     } else {
         "mirrors.json"
     };
-    let json = from_utf8(&read(mirror_path).expect("Couldn't read mirrors from json"))
-        .unwrap()
-        .to_owned();
+    let json = read_to_string(mirror_path)?;
     MirrorList::parse_mirrors(&json)
 }
 
-fn select_mirror(mirror_type: MirrorType, mirrors: &Vec<Mirror>) -> Mirror {
-    match mirror_type {
-        MirrorType::Search => Select::new("Select a search mirror:", mirrors.clone())
-            .prompt()
-            .expect("No valid mirror selected"),
-        MirrorType::Download => Select::new("Select a download mirror:", mirrors.clone())
-            .prompt()
-            .expect("No valid mirror selected"),
-    }
+fn select_mirror(mirror_type: MirrorType, mirrors: &[Mirror]) -> crate::Result<Mirror> {
+    let prompt = match mirror_type {
+        MirrorType::Search => "Select a search mirror:",
+        MirrorType::Download => "Select a download mirror:",
+    };
+    Ok(Select::new(prompt, mirrors.to_vec()).prompt()?)
 }
 
-fn select_search_type() -> SearchType {
+fn select_search_type() -> crate::Result<SearchType> {
     let options = vec![SearchType::NonFiction, SearchType::Fiction];
 
-    Select::new("Select search type:", options)
-        .prompt()
-        .expect("No valid search type selected")
+    Ok(Select::new("Select search type:", options).prompt()?)
+}
+
+fn select_libgen_book(books: &[LibgenBook], prompt: &str) -> crate::Result<LibgenBook> {
+    Ok(Select::new(prompt, books.to_vec()).prompt()?)
 }
 
-fn select_libgen_book(books: &Vec<LibgenBook>, prompt: &str) -> LibgenBook {
-    Select::new(prompt, books.clone())
-        .prompt()
-        .expect("No valid book selected")
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_parameters_ignores_quoted_semicolons() {
+        assert_eq!(
+            split_parameters(r#"attachment; filename="a; b.epub"; size=3"#),
+            vec!["attachment", r#" filename="a; b.epub""#, " size=3"]
+        );
+        assert_eq!(
+            split_parameters(r#"attachment; filename="a\"; b.epub""#),
+            vec!["attachment", r#" filename="a\"; b.epub""#]
+        );
+        assert_eq!(split_parameters("inline"), vec!["inline"]);
+    }
+
+    #[test]
+    fn decode_extended_value_decodes_charsets() {
+        assert_eq!(
+            decode_extended_value("UTF-8''%CE%92%CE%B9%CE%B2%CE%BB%CE%AF%CE%BF.epub").as_deref(),
+            Some("Βιβλίο.epub")
+        );
+        assert_eq!(
+            decode_extended_value("iso-8859-1'en'caf%E9.pdf").as_deref(),
+            Some("café.pdf")
+        );
+        assert_eq!(
+            decode_extended_value("\"utf-8''a%20b.epub\"").as_deref(),
+            Some("a b.epub")
+        );
+        assert_eq!(
+            decode_extended_value("utf-8''100%.epub").as_deref(),
+            Some("100%.epub")
+        );
+    }
+
+    #[test]
+    fn decode_extended_value_rejects_invalid_values() {
+        assert_eq!(decode_extended_value("a%20b.epub"), None);
+        assert_eq!(decode_extended_value("koi8-r''a.epub"), None);
+        assert_eq!(decode_extended_value("utf-8''%FF.epub"), None);
+        assert_eq!(decode_extended_value("utf-8''"), None);
+    }
+
+    #[test]
+    fn parse_filename_prefers_extended_filename() {
+        assert_eq!(
+            parse_filename(
+                r#"attachment; filename="fallback.epub"; filename*=UTF-8''real%20name.epub"#
+            )
+            .as_deref(),
+            Some("real name.epub")
+        );
+        assert_eq!(
+            parse_filename(r#"attachment; FILENAME*=unknown''x.epub; filename="fallback.epub""#)
+                .as_deref(),
+            Some("fallback.epub")
+        );
+    }
+
+    #[test]
+    fn parse_filename_unquotes_filename() {
+        assert_eq!(
+            parse_filename(r#"attachment; filename="Title; \"Quoted\".epub""#).as_deref(),
+            Some(r#"Title; "Quoted".epub"#)
+        );
+        assert_eq!(
+            parse_filename("attachment; filename=plain.pdf").as_deref(),
+            Some("plain.pdf")
+        );
+        assert_eq!(parse_filename(r#"attachment; filename="""#), None);
+        assert_eq!(parse_filename("attachment"), None);
+        assert_eq!(parse_filename("filename=not-a-parameter.epub"), None);
+    }
+
+    #[test]
+    fn url_filename_reads_query_or_last_segment() {
+        let url = |url: &str| Url::parse(url).unwrap();
+
+        assert_eq!(
+            url_filename(&url("https://ipfs.io/ipfs/Qm?filename=A%20Book.epub")).as_deref(),
+            Some("A Book.epub")
+        );
+        assert_eq!(
+            url_filename(&url("https://example.org/main/0/abc/A%20Book.pdf")).as_deref(),
+            Some("A Book.pdf")
+        );
+        assert_eq!(
+            url_filename(&url("https://example.org/get.php?md5=abc")),
+            None
+        );
+        assert_eq!(url_filename(&url("https://example.org/main/abc")), None);
+    }
 }
@@ -0,0 +1,501 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+use crate::{libgen::models::LibgenBook, models::Book};
+
+/// Longest file or directory name written, in bytes. Most filesystems allow 255.
+const MAX_NAME_LENGTH: usize = 200;
+const PART_EXTENSION: &str = ".part";
+const MAX_EXTENSION_LENGTH: usize = 16;
+
+lazy_static! {
+    static ref PLACEHOLDER: Regex = Regex::new(r"\{(\w+)\}").unwrap();
+    /// Brackets left empty by a missing field, e.g. "Title ()"
+    static ref EMPTY_BRACKETS: Regex = Regex::new(r"\s*(\(\s*\)|\[\s*\])").unwrap();
+}
+
+/// Where downloaded books are saved and how they are named.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibraryOptions {
+    /// Directory the books are saved in, the working directory when unset
+    pub directory: Option<PathBuf>,
+    /// Path of a book inside the directory, e.g. `{author}/{title} ({year}).{ext}`.
+    /// When unset the filename the mirror sends is kept.
+    ///
+    /// Fields: `title`, `subtitle`, `author` (the first one), `authors`, `year`, `publisher`,
+    /// `isbn`, `series`, `language`, `ext`, `md5` and `id` (the Google Books id).
+    pub template: Option<String>,
+}
+
+impl LibraryOptions {
+    pub fn directory(&self) -> PathBuf {
+        self.directory.clone().unwrap_or_default()
+    }
+}
+
+/// A libgen book to download and where it goes.
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadTarget<'a> {
+    pub libgen_book: &'a LibgenBook,
+    /// The collection book it was found for, its metadata is preferred over libgen's
+    pub book: Option<&'a Book>,
+    pub library: &'a LibraryOptions,
+}
+
+impl DownloadTarget<'_> {
+    pub fn md5(&self) -> &str {
+        &self.libgen_book.md5
+    }
+
+    /// The unfinished download, in the library directory so it can be moved into place.
+    pub fn part_path(&self) -> PathBuf {
+        self.library
+            .directory()
+            .join(format!("{}{}", self.md5().to_lowercase(), PART_EXTENSION))
+    }
+
+    /// Where the finished download is saved, numbered when the file already exists.
+    pub fn file_path(&self, mirror_filename: Option<&str>) -> PathBuf {
+        // NOTE:(akotro) Like browsers, only the last component of a sent filename is kept
+        let mirror_filename = mirror_filename
+            .and_then(|name| name.rsplit(['/', '\\']).next())
+            .map(sanitize)
+            .filter(|name| !name.is_empty());
+        let extension = file_extension(&self.libgen_book.extension)
+            .or_else(|| {
+                mirror_filename
+                    .as_deref()
+                    .and_then(|name| name.rsplit_once('.'))
+                    .and_then(|(_, extension)| file_extension(extension))
+            })
+            .unwrap_or_default();
+
+        let relative_path = match (&self.library.template, mirror_filename) {
+            (Some(template), _) => self.render(template, &extension),
+            (None, Some(mirror_filename)) => PathBuf::from(mirror_filename),
+            (None, None) => PathBuf::from(file_name(&self.md5().to_lowercase(), &extension)),
+        };
+        unique_path(self.library.directory().join(relative_path))
+    }
+
+    /// Fills in the template, each `/` separated part becomes a sanitised path component.
+    fn render(&self, template: &str, extension: &str) -> PathBuf {
+        let fields = self.fields(extension);
+        let mut components = template
+            .split(['/', '\\'])
+            .map(|component| {
+                let rendered = PLACEHOLDER.replace_all(component, |placeholder: &Captures| {
+                    match fields.get(&placeholder[1]) {
+                        Some(value) => value.clone(),
+                        None => placeholder[0].to_string(),
+                    }
+                });
+                sanitize(&EMPTY_BRACKETS.replace_all(&rendered, ""))
+            })
+            .collect::<Vec<_>>();
+
+        // NOTE:(akotro) An empty directory, e.g. {series} of a book without one, is left out
+        let name = components.pop().unwrap_or_default();
+        let name = match name.rsplit_once('.') {
+            Some((stem, name_extension))
+                if !stem.is_empty() && name_extension.eq_ignore_ascii_case(extension) =>
+            {
+                file_name(stem, extension)
+            }
+            _ if name.is_empty() => file_name(&self.md5().to_lowercase(), extension),
+            _ => file_name(&name, extension),
+        };
+        components
+            .into_iter()
+            .filter(|component| !component.is_empty())
+            .chain(Some(name))
+            .collect()
+    }
+
+    fn fields(&self, extension: &str) -> HashMap<&'static str, String> {
+        let libgen_book = self.libgen_book;
+        let volume_info = self.book.map(|book| &book.volume_info);
+
+        let authors = volume_info
+            .and_then(|volume_info| volume_info.authors.clone())
+            .filter(|authors| !authors.is_empty())
+            .unwrap_or_else(|| {
+                if libgen_book.authors.is_empty() && !libgen_book.author.trim().is_empty() {
+                    vec![libgen_book.author.trim().to_string()]
+                } else {
+                    libgen_book.authors.clone()
+                }
+            });
+        // NOTE:(akotro) Fiction names are "Last, First", commas can't tell them apart there
+        let authors_separator = if authors.iter().any(|author| author.contains(',')) {
+            "; "
+        } else {
+            ", "
+        };
+        let year = volume_info
+            .and_then(|volume_info| volume_info.published_date.as_deref())
+            .and_then(|date| date.get(..4))
+            .filter(|year| year.chars().all(|c| c.is_ascii_digit()))
+            .unwrap_or(&libgen_book.year);
+        let isbn = volume_info
+            .and_then(|volume_info| volume_info.industry_identifiers.as_ref())
+            .and_then(|identifiers| {
+                ["ISBN_13", "ISBN_10"].iter().find_map(|isbn_type| {
+                    identifiers
+                        .iter()
+                        .find(|identifier| identifier.isbn_type == *isbn_type)
+                })
+            })
+            .map(|identifier| identifier.identifier.clone())
+            .unwrap_or_default();
+        let or_libgen = |value: Option<&Option<String>>, libgen_value: &str| {
+            value
+                .and_then(|value| value.clone())
+                .filter(|value| !value.is_empty())
+                .unwrap_or_else(|| libgen_value.to_string())
+        };
+
+        HashMap::from([
+            (
+                "title",
+                or_libgen(volume_info.map(|v| &v.title), &libgen_book.title),
+            ),
+            ("subtitle", or_libgen(volume_info.map(|v| &v.subtitle), "")),
+            ("author", authors.first().cloned().unwrap_or_default()),
+            ("authors", authors.join(authors_separator)),
+            ("year", year.to_string()),
+            (
+                "publisher",
+                or_libgen(volume_info.map(|v| &v.publisher), &libgen_book.publisher),
+            ),
+            ("isbn", isbn),
+            ("series", libgen_book.series.clone()),
+            ("language", libgen_book.language.clone()),
+            ("ext", extension.to_string()),
+            ("md5", libgen_book.md5.to_lowercase()),
+            (
+                "id",
+                self.book.map(|book| book.id.clone()).unwrap_or_default(),
+            ),
+        ])
+    }
+}
+
+/// Makes a single file or directory name safe on every platform: no path separators, control
+/// or reserved characters, no leading or trailing dots and spaces and no reserved Windows names.
+pub fn sanitize(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_whitespace() => ' ',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    let name = name.trim_matches(|c| c == '.' || c == ' ');
+
+    let stem = name.split('.').next().unwrap_or_default();
+    let reserved = matches!(stem.to_uppercase().as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || (stem.len() == 4
+            && stem.is_ascii()
+            && ["COM", "LPT"].contains(&stem[..3].to_uppercase().as_str())
+            && stem[3..].chars().all(|c| c.is_ascii_digit()));
+    if reserved {
+        format!("_{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// The extension a mirror sent, lowercased. `None` unless it's a short run of ASCII letters and
+/// digits, mirrors aren't trusted to keep e.g. `epub/../../x` out of it.
+fn file_extension(extension: &str) -> Option<String> {
+    let extension = extension.trim().to_ascii_lowercase();
+    let valid = !extension.is_empty()
+        && extension.len() <= MAX_EXTENSION_LENGTH
+        && extension.chars().all(|c| c.is_ascii_alphanumeric());
+    Some(extension).filter(|_| valid)
+}
+
+/// `stem.extension`, with the stem shortened so the name stays under `MAX_NAME_LENGTH`.
+fn file_name(stem: &str, extension: &str) -> String {
+    let suffix = if extension.is_empty() {
+        String::new()
+    } else {
+        format!(".{}", extension)
+    };
+    let mut length = MAX_NAME_LENGTH.saturating_sub(suffix.len()).min(stem.len());
+    while !stem.is_char_boundary(length) {
+        length -= 1;
+    }
+    format!("{}{}", stem[..length].trim_end(), suffix)
+}
+
+/// `path`, or `name (2).ext`, `name (3).ext`... when it's taken.
+fn unique_path(path: PathBuf) -> PathBuf {
+    if !path.exists() {
+        return path;
+    }
+
+    let parent = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    (2..)
+        .map(|number| parent.join(file_name(&format!("{} ({})", stem, number), &extension)))
+        .find(|candidate| !candidate.exists())
+        .unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Component, process};
+
+    use super::*;
+    use crate::{libgen::models::Topic, models::VolumeInfo};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rubook-library-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn libgen_book() -> LibgenBook {
+        LibgenBook {
+            title: "Libgen Title".to_string(),
+            author: "First Author, Second Author".to_string(),
+            authors: vec!["First Author".to_string(), "Second Author".to_string()],
+            year: "2001".to_string(),
+            extension: "EPUB".to_string(),
+            md5: "0123456789ABCDEF0123456789ABCDEF".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn library(directory: &Path, template: Option<&str>) -> LibraryOptions {
+        LibraryOptions {
+            directory: Some(directory.to_path_buf()),
+            template: template.map(String::from),
+        }
+    }
+
+    fn target<'a>(libgen_book: &'a LibgenBook, library: &'a LibraryOptions) -> DownloadTarget<'a> {
+        DownloadTarget {
+            libgen_book,
+            book: None,
+            library,
+        }
+    }
+
+    #[test]
+    fn sanitize_replaces_separators_and_reserved_characters() {
+        assert_eq!(sanitize("a/b\\c:d*e?f\"g<h>i|j"), "a_b_c_d_e_f_g_h_i_j");
+        assert_eq!(sanitize("bell\u{7}"), "bell_");
+        assert_eq!(sanitize("  The \t Title\n"), "The Title");
+        assert_eq!(sanitize("..hidden.. "), "hidden");
+        assert_eq!(sanitize(".."), "");
+    }
+
+    #[test]
+    fn sanitize_escapes_reserved_windows_names() {
+        assert_eq!(sanitize("CON"), "_CON");
+        assert_eq!(sanitize("nul.epub"), "_nul.epub");
+        assert_eq!(sanitize("com1"), "_com1");
+        assert_eq!(sanitize("LPT9.txt"), "_LPT9.txt");
+        assert_eq!(sanitize("COM10"), "COM10");
+        assert_eq!(sanitize("Console"), "Console");
+    }
+
+    #[test]
+    fn render_fills_in_fields_and_drops_empty_parts() {
+        let libgen_book = libgen_book();
+        let library = library(Path::new("library"), None);
+        let target = target(&libgen_book, &library);
+
+        assert_eq!(
+            target.render(
+                "{author}/{series}/{title} ({year}) [{language}].{ext}",
+                "epub"
+            ),
+            Path::new("First Author").join("Libgen Title (2001).epub")
+        );
+        assert_eq!(
+            target.render("{authors} - {unknown}", "epub"),
+            PathBuf::from("First Author, Second Author - {unknown}.epub")
+        );
+        assert_eq!(
+            target.render("{series}", "epub"),
+            PathBuf::from("0123456789abcdef0123456789abcdef.epub")
+        );
+    }
+
+    #[test]
+    fn render_keeps_fiction_names_whole() {
+        let libgen_book = LibgenBook {
+            title: "Dune: The Machine Crusade".to_string(),
+            author: "Herbert, Frank; Anderson, Kevin J.".to_string(),
+            authors: vec![
+                "Herbert, Frank".to_string(),
+                "Anderson, Kevin J.".to_string(),
+            ],
+            topic: Topic::Fiction,
+            ..libgen_book()
+        };
+        let library = library(Path::new("library"), None);
+        let target = target(&libgen_book, &library);
+
+        assert_eq!(
+            target.render("{author}/{title}", "epub"),
+            Path::new("Herbert, Frank").join("Dune_ The Machine Crusade.epub")
+        );
+        assert_eq!(
+            target.render("{authors} - {title}", "epub"),
+            PathBuf::from("Herbert, Frank; Anderson, Kevin J. - Dune_ The Machine Crusade.epub")
+        );
+    }
+
+    #[test]
+    fn render_uses_the_whole_author_without_a_list() {
+        let libgen_book = LibgenBook {
+            authors: Vec::new(),
+            ..libgen_book()
+        };
+        let library = library(Path::new("library"), None);
+        let target = target(&libgen_book, &library);
+
+        assert_eq!(
+            target.render("{author}", "epub"),
+            PathBuf::from("First Author, Second Author.epub")
+        );
+    }
+
+    #[test]
+    fn render_prefers_google_books_metadata() {
+        let libgen_book = libgen_book();
+        let library = library(Path::new("library"), None);
+        let book = Book {
+            id: "google-id".to_string(),
+            volume_info: VolumeInfo {
+                title: Some("Google Title".to_string()),
+                authors: Some(vec!["Google Author".to_string()]),
+                published_date: Some("1999-05-01".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let target = DownloadTarget {
+            book: Some(&book),
+            ..target(&libgen_book, &library)
+        };
+
+        assert_eq!(
+            target.render("{author}/{title} ({year}) {id}", "pdf"),
+            Path::new("Google Author").join("Google Title (1999) google-id.pdf")
+        );
+    }
+
+    #[test]
+    fn render_sanitizes_field_values() {
+        let libgen_book = LibgenBook {
+            title: "../../Escape: A Story".to_string(),
+            author: "..".to_string(),
+            authors: vec!["..".to_string()],
+            ..libgen_book()
+        };
+        let library = library(Path::new("library"), None);
+        let target = target(&libgen_book, &library);
+
+        assert_eq!(
+            target.render("{author}/{title}", "epub"),
+            PathBuf::from("_.._Escape_ A Story.epub")
+        );
+    }
+
+    #[test]
+    fn file_path_ignores_unsafe_extensions() {
+        let dir = temp_dir("extension");
+        let library = library(&dir, Some("{title}"));
+        for extension in ["epub/../../x", "..", "ep ub", "", "toolongtobeanextension"] {
+            let libgen_book = LibgenBook {
+                extension: extension.to_string(),
+                ..libgen_book()
+            };
+            let target = target(&libgen_book, &library);
+
+            assert_eq!(target.file_path(None), dir.join("Libgen Title"));
+            assert_eq!(
+                target.file_path(Some("../mirror name.PDF")),
+                dir.join("Libgen Title.pdf")
+            );
+            assert_eq!(
+                target.file_path(Some("mirror name.p/df")),
+                dir.join("Libgen Title")
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_path_stays_inside_the_library() {
+        let dir = temp_dir("inside");
+        let libgen_book = LibgenBook {
+            extension: "epub/../../x".to_string(),
+            ..libgen_book()
+        };
+        for template in [None, Some("{title}.{ext}"), Some("../{ext}/{title}")] {
+            let library = library(&dir, template);
+            let target = target(&libgen_book, &library);
+            for mirror_filename in [None, Some("../../etc/passwd"), Some("..\\..\\x.epub")] {
+                let path = target.file_path(mirror_filename);
+                assert!(path.starts_with(&dir), "{:?}", path);
+                assert!(
+                    !path.components().any(|c| c == Component::ParentDir),
+                    "{:?}",
+                    path
+                );
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unique_path_numbers_taken_names() {
+        let dir = temp_dir("unique");
+        let path = dir.join("Title.epub");
+        assert_eq!(unique_path(path.clone()), path);
+
+        fs::write(&path, "").unwrap();
+        assert_eq!(unique_path(path.clone()), dir.join("Title (2).epub"));
+
+        fs::write(dir.join("Title (2).epub"), "").unwrap();
+        assert_eq!(unique_path(path.clone()), dir.join("Title (3).epub"));
+
+        fs::write(dir.join("Title"), "").unwrap();
+        assert_eq!(unique_path(dir.join("Title")), dir.join("Title (2)"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_name_stays_under_the_length_limit() {
+        let stem = "é".repeat(MAX_NAME_LENGTH);
+        let name = file_name(&stem, "epub");
+        assert!(name.len() <= MAX_NAME_LENGTH);
+        assert!(name.ends_with(".epub"));
+        assert_eq!(file_name("Title ", ""), "Title");
+    }
+}
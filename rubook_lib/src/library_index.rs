@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
+use rusqlite::{params, Connection, Row};
+use serde::Serialize;
+
+use crate::libgen::models::DownloadResult;
+
+/// Bumped with every change to the schema, see `migrate`
+const SCHEMA_VERSION: i32 = 1;
+const PART_EXTENSION: &str = "part";
+
+/// A downloaded file and the collection book it was downloaded for.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LibraryEntry {
+    pub book_id: String,
+    /// Md5 of the libgen file, an epub whose metadata was rewritten no longer hashes to it
+    pub md5: String,
+    pub file_path: PathBuf,
+    pub size: u64,
+    pub downloaded_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Present,
+    Missing,
+    /// The file is there but its size isn't the recorded one
+    Changed,
+}
+
+/// What `LibraryIndex::rescan` changed.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RescanReport {
+    /// Entries whose file was moved, they point to where it was found
+    pub relinked: Vec<LibraryEntry>,
+    /// Entries whose file changed size, with the new size
+    pub updated: Vec<LibraryEntry>,
+    /// Entries whose file couldn't be found anymore
+    pub removed: Vec<LibraryEntry>,
+}
+
+/// Local SQLite index of the books that have been downloaded and where their files are.
+pub struct LibraryIndex {
+    connection: Connection,
+}
+
+impl LibraryIndex {
+    pub fn open(path: &Path) -> crate::Result<LibraryIndex> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let connection = Connection::open(path)?;
+        migrate(&connection)?;
+
+        Ok(LibraryIndex { connection })
+    }
+
+    /// Records a finished download, replacing an earlier download of the same file.
+    pub fn record(&self, book_id: &str, result: &DownloadResult) -> crate::Result<LibraryEntry> {
+        let file_path = fs::canonicalize(&result.file_path)?;
+        let entry = LibraryEntry {
+            book_id: book_id.to_string(),
+            md5: result.md5.to_lowercase(),
+            size: fs::metadata(&file_path)?.len(),
+            file_path,
+            downloaded_at: Utc::now(),
+        };
+
+        self.connection.execute(
+            "INSERT INTO downloads (book_id, md5, file_path, size, downloaded_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (book_id, md5) DO UPDATE SET
+                file_path = excluded.file_path,
+                size = excluded.size,
+                downloaded_at = excluded.downloaded_at",
+            params![
+                entry.book_id,
+                entry.md5,
+                entry.file_path.to_string_lossy(),
+                entry.size as i64,
+                entry.downloaded_at,
+            ],
+        )?;
+
+        Ok(entry)
+    }
+
+    /// All the entries, oldest download first.
+    pub fn entries(&self) -> crate::Result<Vec<LibraryEntry>> {
+        let mut statement = self.connection.prepare(
+            "SELECT book_id, md5, file_path, size, downloaded_at
+             FROM downloads ORDER BY downloaded_at, book_id",
+        )?;
+        let entries = statement
+            .query_map([], entry_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    /// The latest download of a book whose file is still there.
+    pub fn downloaded(&self, book_id: &str) -> crate::Result<Option<LibraryEntry>> {
+        let mut statement = self.connection.prepare(
+            "SELECT book_id, md5, file_path, size, downloaded_at
+             FROM downloads WHERE book_id = ?1 ORDER BY downloaded_at DESC",
+        )?;
+        let entries = statement
+            .query_map([book_id], entry_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries.into_iter().find(|entry| entry.file_path.is_file()))
+    }
+
+    /// Checks that the file of every entry is still there, with the size it was downloaded with.
+    pub fn verify(&self) -> crate::Result<Vec<(LibraryEntry, FileStatus)>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .map(|entry| {
+                let status = file_status(&entry);
+                (entry, status)
+            })
+            .collect())
+    }
+
+    /// Brings the index in line with the files under `dir`: entries whose file was moved are
+    /// pointed to it, entries whose file is gone are removed and changed sizes are updated.
+    ///
+    /// A moved file is recognised by its name and size, or else by its md5.
+    pub fn rescan(&self, dir: &Path) -> crate::Result<RescanReport> {
+        let entries = self.entries()?;
+        let mut report = RescanReport::default();
+
+        let indexed = entries
+            .iter()
+            .map(|entry| entry.file_path.clone())
+            .collect::<Vec<_>>();
+        // NOTE:(akotro) An unset library directory is the working directory
+        let dir = Some(dir)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let mut unindexed = Vec::new();
+        if dir.is_dir() {
+            collect_files(&fs::canonicalize(dir)?, &mut unindexed)?;
+        }
+        unindexed.retain(|path| !indexed.contains(path));
+        let mut md5s = HashMap::new();
+
+        for mut entry in entries {
+            match file_status(&entry) {
+                FileStatus::Present => {}
+                FileStatus::Changed => {
+                    entry.size = fs::metadata(&entry.file_path)?.len();
+                    self.update(&entry)?;
+                    report.updated.push(entry);
+                }
+                FileStatus::Missing => match find_moved(&entry, &unindexed, &mut md5s)? {
+                    Some(index) => {
+                        entry.file_path = unindexed.remove(index);
+                        entry.size = fs::metadata(&entry.file_path)?.len();
+                        self.update(&entry)?;
+                        report.relinked.push(entry);
+                    }
+                    None => {
+                        self.connection.execute(
+                            "DELETE FROM downloads WHERE book_id = ?1 AND md5 = ?2",
+                            params![entry.book_id, entry.md5],
+                        )?;
+                        report.removed.push(entry);
+                    }
+                },
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn update(&self, entry: &LibraryEntry) -> crate::Result<()> {
+        self.connection.execute(
+            "UPDATE downloads SET file_path = ?3, size = ?4 WHERE book_id = ?1 AND md5 = ?2",
+            params![
+                entry.book_id,
+                entry.md5,
+                entry.file_path.to_string_lossy(),
+                entry.size as i64,
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+fn migrate(connection: &Connection) -> crate::Result<()> {
+    let version: i32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version < 1 {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS downloads (
+                book_id TEXT NOT NULL,
+                md5 TEXT NOT NULL,
+                file_path TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                downloaded_at TEXT NOT NULL,
+                PRIMARY KEY (book_id, md5)
+            );",
+        )?;
+    }
+    connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    Ok(())
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<LibraryEntry> {
+    Ok(LibraryEntry {
+        book_id: row.get(0)?,
+        md5: row.get(1)?,
+        file_path: PathBuf::from(row.get::<_, String>(2)?),
+        size: row.get::<_, i64>(3)? as u64,
+        downloaded_at: row.get(4)?,
+    })
+}
+
+fn file_status(entry: &LibraryEntry) -> FileStatus {
+    match fs::metadata(&entry.file_path) {
+        Ok(metadata) if metadata.is_file() && metadata.len() == entry.size => FileStatus::Present,
+        Ok(metadata) if metadata.is_file() => FileStatus::Changed,
+        _ => FileStatus::Missing,
+    }
+}
+
+/// Files under `dir`, leaving out unfinished downloads. Symlinks aren't followed.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for dir_entry in fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let file_type = dir_entry.file_type()?;
+        let path = dir_entry.path();
+        if file_type.is_dir() {
+            collect_files(&path, files)?;
+        } else if file_type.is_file()
+            && path.extension().and_then(|extension| extension.to_str()) != Some(PART_EXTENSION)
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The position in `files` of the moved file of `entry`, hashing files only when no file
+/// has the same name and size.
+fn find_moved(
+    entry: &LibraryEntry,
+    files: &[PathBuf],
+    md5s: &mut HashMap<PathBuf, String>,
+) -> crate::Result<Option<usize>> {
+    let same_name = files.iter().position(|path| {
+        path.file_name() == entry.file_path.file_name()
+            && fs::metadata(path).is_ok_and(|metadata| metadata.len() == entry.size)
+    });
+    if same_name.is_some() {
+        return Ok(same_name);
+    }
+
+    for (index, path) in files.iter().enumerate() {
+        let md5 = match md5s.get(path) {
+            Some(md5) => md5.clone(),
+            None => {
+                let mut hasher = Md5::new();
+                io::copy(&mut File::open(path)?, &mut hasher)?;
+                let md5 = hex::encode(hasher.finalize());
+                md5s.insert(path.clone(), md5.clone());
+                md5
+            }
+        };
+        if md5 == entry.md5 {
+            return Ok(Some(index));
+        }
+    }
+    Ok(None)
+}
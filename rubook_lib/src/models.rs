@@ -11,7 +11,7 @@ pub struct Book {
 }
 
 impl Book {
-    pub fn print_book_info(&self) -> Result<(), &'static str> {
+    pub fn print_book_info(&self) -> crate::Result<()> {
         println!("Volume Info: {:?}", self.volume_info);
         println!("Access Info: {:?}", self.access_info);
 
@@ -87,6 +87,18 @@ pub struct VolumeInfo {
     pub description: Option<String>,
     pub authors: Option<Vec<String>>,
     pub industry_identifiers: Option<Vec<IndustryIdentifier>>,
+    pub image_links: Option<ImageLinks>,
+}
+
+impl VolumeInfo {
+    /// Url of the largest cover Google Books has for the volume.
+    pub fn cover_url(&self) -> Option<&str> {
+        let image_links = self.image_links.as_ref()?;
+        image_links
+            .thumbnail
+            .as_deref()
+            .or(image_links.small_thumbnail.as_deref())
+    }
 }
 
 impl fmt::Display for VolumeInfo {
@@ -110,6 +122,13 @@ pub struct IndustryIdentifier {
     pub identifier: String,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageLinks {
+    pub small_thumbnail: Option<String>,
+    pub thumbnail: Option<String>,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AccessInfo {
@@ -142,6 +161,8 @@ pub struct Error {
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Response {
+    /// Google's estimate of the number of results, it can change between pages
+    pub total_items: Option<u32>,
     pub items: Option<Vec<Book>>,
     pub error: Option<Error>,
 }
@@ -180,22 +201,21 @@ impl<T> ApiResponse<T>
 where
     T: serde::de::DeserializeOwned,
 {
-    pub fn from_response_body(response_body: &str) -> Result<T, Box<dyn std::error::Error>> {
+    pub fn from_response_body(response_body: &str) -> crate::Result<T> {
         let api_response: ApiResponse<T> = serde_json::from_str(response_body)?;
-        if api_response.success {
-            if let Some(data) = api_response.data {
-                Ok(data)
-            } else {
-                Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Data is missing in successful response",
-                )))
-            }
+        api_response.into_result(None)
+    }
+
+    pub fn into_result(self, status: Option<u16>) -> crate::Result<T> {
+        if self.success {
+            self.data.ok_or_else(|| {
+                crate::Error::Parse("Data is missing in successful response".to_string())
+            })
         } else {
-            Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                api_response.message,
-            )))
+            Err(crate::Error::Api {
+                status,
+                message: self.message,
+            })
         }
     }
 }
@@ -0,0 +1,43 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc::{self, UnboundedReceiver},
+};
+
+/// Answers every request with `status` and `body`. Returns the address to send them to and
+/// the paths that were requested, in order.
+pub async fn serve(
+    status: &'static str,
+    body: &'static str,
+) -> (String, UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("http://{}", listener.local_addr().unwrap());
+    let (paths, requested_paths) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+
+            let request = String::from_utf8_lossy(&request);
+            if let Some(path) = request.split_whitespace().nth(1) {
+                let _ = paths.send(path.to_string());
+            }
+        }
+    });
+    (address, requested_paths)
+}
@@ -1,31 +1,53 @@
 use core::fmt;
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
-use inquire::{min_length, MultiSelect, Password, PasswordDisplayMode, Select, Text};
+use inquire::{min_length, Confirm, MultiSelect, Password, PasswordDisplayMode, Select, Text};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use tokio::task::JoinHandle;
 
 use crate::{
-    backend_util, libgen::mirrors::Mirror, libgen_util::libgen_book_download, models::Book,
+    backend_util::BackendClient,
+    covers::CoverCache,
+    libgen::{mirrors::MirrorChecks, search::SearchOptions},
+    libgen_util::{book_label, libgen_batch_download},
+    library::LibraryOptions,
+    library_index::LibraryIndex,
+    models::Book,
 };
 
+fn prompt_credentials(confirm_password: bool) -> crate::Result<(String, String)> {
+    let username = Text::new("Enter your username:").prompt()?;
+    let password =
+        Password::new("Enter your password: ").with_display_mode(PasswordDisplayMode::Masked);
+    let password = if confirm_password {
+        password.with_validator(min_length!(8))
+    } else {
+        password.without_confirmation()
+    };
+    Ok((username, password.prompt()?))
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
     pub token: String,
+    /// Exchanged for a new token at `/auth/refresh`, see `AuthTokens`
+    #[serde(default)]
+    pub refresh_token: String,
     pub username: String,
+    /// Only filled in on the backend, where it's the password hash
+    #[serde(default, skip_serializing)]
     pub password: String,
     pub collection: Vec<Book>,
 }
 
 impl fmt::Display for User {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        println!("\n{}'s collection:\n", self.username);
+        writeln!(f, "\n{}'s collection:\n", self.username)?;
         if !self.collection.is_empty() {
             self.collection
                 .iter()
-                .try_for_each(|book| write!(f, "{}\n", book))
+                .try_for_each(|book| writeln!(f, "{}", book))
         } else {
             write!(f, "No books in your collection yet")
         }
@@ -34,59 +56,87 @@ impl fmt::Display for User {
 
 #[derive(Serialize, Deserialize)]
 pub struct UserClaims {
+    /// Id of the user the token was issued to
     pub sub: String,
     pub exp: usize,
+    #[serde(default)]
+    pub role: String,
+    /// Id of the backend session the token belongs to, revoking the session revokes the token
+    #[serde(default)]
+    pub jti: String,
 }
 
-pub async fn register(client: &Arc<Client>) -> Option<User> {
-    let username = Text::new("Enter your username:")
-        .prompt()
-        .expect("Failed to get username");
-    let password = Password::new("Enter your password: ")
-        .with_display_mode(PasswordDisplayMode::Masked)
-        .with_validator(min_length!(8))
-        .prompt()
-        .expect("Failed to get password");
-
-    match backend_util::register_user(client, username, password).await {
-        Ok(db_user) => {
-            println!("User '{}' created", db_user.username);
-            Some(User {
-                id: db_user.id,
-                token: db_user.token,
-                username: db_user.username,
-                password: db_user.password,
-                collection: vec![],
-            })
-        }
-        Err(_) => {
-            println!("Failed to create user");
-            None
+/// A short lived access token and the refresh token that replaces it once it expires.
+/// Refresh tokens are single use, every refresh returns a new pair.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthTokens {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+impl From<&User> for AuthTokens {
+    fn from(user: &User) -> Self {
+        AuthTokens {
+            token: user.token.clone(),
+            refresh_token: user.refresh_token.clone(),
         }
     }
 }
 
-pub async fn login(client: &Arc<Client>) -> Option<User> {
-    let username = Text::new("Enter your username:")
-        .prompt()
-        .expect("Failed to get username");
-    let password = Password::new("Enter your password: ")
-        .with_display_mode(PasswordDisplayMode::Masked)
-        .without_confirmation()
-        .prompt()
-        .expect("Failed to get password");
-
-    if let Ok(user) = backend_util::login_user(client, username, password).await {
-        println!("Welcome back, {}", user.username);
-        Some(user)
-    } else {
-        println!("Failed to login");
-        None
+/// Body of `PUT /users/{id}`. `None` keeps a field. The current password is required unless
+/// an admin changes another user.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct AccountUpdate {
+    #[serde(default)]
+    pub current_password: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_password: Option<String>,
+}
+
+#[derive(Debug)]
+enum AccountOption {
+    ChangeUsername,
+    ChangePassword,
+    Back,
+}
+
+impl fmt::Display for AccountOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccountOption::ChangeUsername => write!(f, "Change your username"),
+            AccountOption::ChangePassword => write!(f, "Change your password"),
+            AccountOption::Back => write!(f, "Back"),
+        }
     }
 }
 
+pub async fn register(backend: &BackendClient) -> crate::Result<User> {
+    let (username, password) = prompt_credentials(true)?;
+
+    let db_user = backend.register_user(username, password).await?;
+    println!("User '{}' created", db_user.username);
+    Ok(User {
+        id: db_user.id,
+        token: db_user.token,
+        refresh_token: db_user.refresh_token,
+        username: db_user.username,
+        password: db_user.password,
+        collection: vec![],
+    })
+}
+
+pub async fn login(backend: &BackendClient) -> crate::Result<User> {
+    let (username, password) = prompt_credentials(false)?;
+
+    let user = backend.login_user(username, password).await?;
+    println!("Welcome back, {}", user.username);
+    Ok(user)
+}
+
 impl User {
-    pub fn view_collection(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn view_collection(&self) -> crate::Result<()> {
         let selected_book =
             Select::new("Select books to view:", self.collection.clone()).prompt()?;
         selected_book.print_book_info()?;
@@ -94,40 +144,97 @@ impl User {
         Ok(())
     }
 
+    /// Adds the books to the collection, skipping the ones that are already in it.
     pub async fn add_books(
         &mut self,
-        client: &Arc<Client>,
-        books: HashMap<String, Book>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let select_items = books.values().map(|book| book).collect::<Vec<_>>();
-        let selected_books =
-            MultiSelect::new("Select books to add to your collection:", select_items).prompt()?;
-
-        let mut create_book_futures = Vec::new();
-        for book in selected_books {
-            if let Some(book) = books.get(&book.id) {
-                // println!("You selected: {}: {}", &book.id, book.volume_info);
-                self.collection.push(book.clone());
-                create_book_futures.push(backend_util::create_book(
-                    client,
-                    self.token.as_str(),
-                    &book,
-                    &self.id,
-                ))
-            }
+        backend: &BackendClient,
+        books: Vec<Book>,
+    ) -> crate::Result<()> {
+        let (owned_books, new_books): (Vec<_>, Vec<_>) = books
+            .into_iter()
+            .partition(|book| self.collection.iter().any(|owned| owned.id == book.id));
+        for book in owned_books {
+            println!("Already in your collection: {}", book);
         }
+
+        let create_book_futures = new_books
+            .iter()
+            .map(|book| backend.create_book(book, &self.id));
         let create_book_results = futures::future::join_all(create_book_futures).await;
-        for result in create_book_results {
-            result?;
+
+        let mut first_error = None;
+        for (book, result) in new_books.into_iter().zip(create_book_results) {
+            match result {
+                Ok(_) => self.collection.push(book),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
         }
 
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Changes the username or password. Changing the password logs out every other session.
+    pub async fn account_settings(&mut self, backend: &BackendClient) -> crate::Result<()> {
+        let options = vec![
+            AccountOption::ChangeUsername,
+            AccountOption::ChangePassword,
+            AccountOption::Back,
+        ];
+        let update = match Select::new("Account settings:", options).prompt()? {
+            AccountOption::ChangeUsername => AccountUpdate {
+                username: Some(
+                    Text::new("Enter your new username:")
+                        .with_initial_value(&self.username)
+                        .prompt()?,
+                ),
+                ..Default::default()
+            },
+            AccountOption::ChangePassword => AccountUpdate {
+                new_password: Some(
+                    Password::new("Enter your new password:")
+                        .with_display_mode(PasswordDisplayMode::Masked)
+                        .with_validator(min_length!(8))
+                        .prompt()?,
+                ),
+                ..Default::default()
+            },
+            AccountOption::Back => return Ok(()),
+        };
+        let current_password = Password::new("Enter your current password:")
+            .with_display_mode(PasswordDisplayMode::Masked)
+            .without_confirmation()
+            .prompt()?;
+        let password_changed = update.new_password.is_some();
+
+        let updated_user = backend
+            .update_user(
+                &self.id,
+                &AccountUpdate {
+                    current_password,
+                    ..update
+                },
+            )
+            .await?;
+        self.username = updated_user.username;
+        if !updated_user.token.is_empty() {
+            self.token = updated_user.token;
+            self.refresh_token = updated_user.refresh_token;
+        }
+
+        if password_changed {
+            println!("Password changed, your other sessions have been logged out");
+        } else {
+            println!("Username changed to '{}'", self.username);
+        }
         Ok(())
     }
 
-    pub async fn delete_books(
-        &mut self,
-        client: &Arc<Client>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn delete_books(&mut self, backend: &BackendClient) -> crate::Result<()> {
         if !self.collection.is_empty() {
             let books_to_delete = MultiSelect::new(
                 "Select books to delete from your collection:",
@@ -140,14 +247,7 @@ impl User {
 
             let delete_book_futures = books_to_delete
                 .iter()
-                .map(|book| {
-                    backend_util::delete_book(
-                        client,
-                        self.token.as_str(),
-                        &self.id,
-                        book.id.clone(),
-                    )
-                })
+                .map(|book| backend.delete_book(&self.id, book.id.clone()))
                 .collect::<Vec<_>>();
             let delete_book_results = futures::future::join_all(delete_book_futures).await;
             for result in delete_book_results {
@@ -163,13 +263,59 @@ impl User {
     pub async fn download_books(
         &mut self,
         client: &Arc<Client>,
-        mirror_handles: &mut Vec<JoinHandle<Result<Vec<Mirror>, String>>>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        mirror_checks: &MirrorChecks,
+        options: &SearchOptions,
+        library: &LibraryOptions,
+        index: &LibraryIndex,
+        covers: Option<&mut CoverCache>,
+    ) -> crate::Result<()> {
         if !self.collection.is_empty() {
-            let selected_book =
-                Select::new("Select books to download:", self.collection.clone()).prompt()?;
+            let selected_books =
+                MultiSelect::new("Select books to download:", self.collection.clone()).prompt()?;
 
-            libgen_book_download(selected_book, client, mirror_handles).await?;
+            let mut books = Vec::new();
+            for book in selected_books {
+                match index.downloaded(&book.id)? {
+                    Some(entry) => println!(
+                        "  SKIPPED {}: already downloaded to {}",
+                        book_label(&book),
+                        entry.file_path.display()
+                    ),
+                    None => books.push(book),
+                }
+            }
+            if books.is_empty() {
+                return Ok(());
+            }
+            let covers = match covers {
+                Some(covers) => Confirm::new("Embed the covers into downloaded epubs?")
+                    .with_default(false)
+                    .prompt()?
+                    .then_some(covers),
+                None => None,
+            };
+
+            let results =
+                libgen_batch_download(books, client, mirror_checks, options, library, covers)
+                    .await?;
+            let succeeded = results.iter().filter(|(_, result)| result.is_ok()).count();
+            println!("\nDownloaded {} of {} books", succeeded, results.len());
+            for (book, result) in results {
+                match result {
+                    Ok(download) => {
+                        println!(
+                            "  OK      {} -> {} ({})",
+                            book_label(&book),
+                            download.file_path,
+                            download.mirror
+                        );
+                        if let Err(e) = index.record(&book.id, &download) {
+                            eprintln!("Couldn't add {} to the library index: {}", book.id, e);
+                        }
+                    }
+                    Err(e) => println!("  FAILED  {}: {}", book_label(&book), e),
+                }
+            }
         } else {
             println!("No books in your collection to download");
         }
@@ -0,0 +1,169 @@
+use rubook_lib::libgen::{
+    models::{LibgenBook, Topic},
+    parse::{parse_fiction_results, parse_non_fiction_results},
+};
+
+const NON_FICTION: &str = include_str!("fixtures/non_fiction_simple.html");
+const FICTION: &str = include_str!("fixtures/fiction.html");
+
+#[test]
+fn non_fiction_rows_keep_page_order_and_skip_stray_hashes() {
+    let rows = parse_non_fiction_results(NON_FICTION);
+
+    let md5s = rows.iter().map(|row| row.md5.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        md5s,
+        [
+            "1A3C6D0B6A1E08E5D8C5C3F0A1C8B7E2",
+            "9F2E4C1D8B7A6E5F4C3B2A1D0E9F8C7B",
+            "0B9C77E3F1A24D6B8E5C4A3B2D1F0E9A",
+            "C4CA4238A0B923820DCC509A6F75849B",
+        ]
+    );
+}
+
+#[test]
+fn non_fiction_row_fields() {
+    let rows = parse_non_fiction_results(NON_FICTION);
+    let row = &rows[0];
+
+    assert_eq!(row.id, "2388134");
+    assert_eq!(row.authors, ["Steve Klabnik", "Carol Nichols"]);
+    assert_eq!(row.title, "The Rust Programming Language");
+    assert_eq!(row.series, "");
+    assert_eq!(row.publisher, "No Starch Press");
+    assert_eq!(row.year, "2018");
+    assert_eq!(row.pages, "552[552]");
+    assert_eq!(row.language, "English");
+    assert_eq!(row.size, "5 Mb");
+    assert_eq!(row.extension, "epub");
+    assert_eq!(
+        row.mirror_links,
+        [
+            "http://library.lol/main/1A3C6D0B6A1E08E5D8C5C3F0A1C8B7E2",
+            "http://libgen.lc/ads.php?md5=1A3C6D0B6A1E08E5D8C5C3F0A1C8B7E2",
+            "https://z-library.se/md5/1A3C6D0B6A1E08E5D8C5C3F0A1C8B7E2",
+            "https://libgen.pw/item?id=2388134",
+            "http://bookfi.net/md5/1A3C6D0B6A1E08E5D8C5C3F0A1C8B7E2",
+        ]
+    );
+}
+
+#[test]
+fn non_fiction_title_excludes_series_and_isbns() {
+    let rows = parse_non_fiction_results(NON_FICTION);
+
+    assert_eq!(rows[1].series, "No Starch Rust");
+    assert_eq!(rows[1].title, "The Rust Programming Language, 2nd Edition");
+    assert_eq!(rows[1].extension, "pdf");
+    assert_eq!(
+        rows[2].title,
+        "Programming Rust: Fast, Safe Systems Development & More"
+    );
+    assert_eq!(rows[2].authors.len(), 3);
+    assert_eq!(rows[3].title, "Язык программирования Rust");
+    assert_eq!(rows[3].pages, "");
+    assert_eq!(rows[3].mirror_links.len(), 1);
+}
+
+#[test]
+fn non_fiction_row_into_libgen_book() {
+    let rows = parse_non_fiction_results(NON_FICTION);
+    let book = LibgenBook::from(rows[0].clone());
+
+    assert_eq!(book.md5, "1A3C6D0B6A1E08E5D8C5C3F0A1C8B7E2");
+    assert_eq!(book.author, "Steve Klabnik, Carol Nichols");
+    assert_eq!(book.authors, ["Steve Klabnik", "Carol Nichols"]);
+    assert_eq!(book.filesize, (5 * 1024 * 1024).to_string());
+
+    let book = LibgenBook::from(rows[3].clone());
+    assert_eq!(book.filesize, (812 * 1024).to_string());
+}
+
+#[test]
+fn non_fiction_page_without_results() {
+    assert!(parse_non_fiction_results("<html><body>No files were found</body></html>").is_empty());
+}
+
+#[test]
+fn fiction_rows() {
+    let books = parse_fiction_results(FICTION);
+
+    let titles = books
+        .iter()
+        .map(|book| book.title.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        titles,
+        ["Dune", "Dune: The Machine Crusade", "Dune Messiah"]
+    );
+
+    let book = &books[0];
+    assert_eq!(book.md5, "2B58D4C5D16E4F7C2C1C7A3A2B4C6F1E");
+    assert_eq!(book.author, "Herbert, Frank");
+    assert_eq!(book.authors, ["Herbert, Frank"]);
+    assert_eq!(book.series, "Dune Chronicles - 1");
+    assert_eq!(book.language, "English");
+    assert_eq!(book.extension, "epub");
+    assert_eq!(book.filesize, ((1.1 * 1024.0 * 1024.0) as u64).to_string());
+
+    assert_eq!(books[1].md5, "7E1F3B9C0A2D4E6F8A1B3C5D7E9F0A2B");
+    assert_eq!(books[1].author, "Herbert, Frank; Anderson, Kevin J.");
+    assert_eq!(books[1].authors, ["Herbert, Frank", "Anderson, Kevin J."]);
+    assert_eq!(books[1].series, "");
+    assert_eq!(books[1].extension, "mobi");
+}
+
+#[test]
+fn fiction_md5s_come_from_title_links() {
+    let books = parse_fiction_results(FICTION);
+
+    let md5s = books
+        .iter()
+        .map(|book| book.md5.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        md5s,
+        [
+            "2B58D4C5D16E4F7C2C1C7A3A2B4C6F1E",
+            "7E1F3B9C0A2D4E6F8A1B3C5D7E9F0A2B",
+            "5D41402ABC4B2A76B9719D911017C592",
+        ]
+    );
+    assert!(books.iter().all(|book| book.topic == Topic::Fiction));
+}
+
+#[test]
+fn fiction_sizes_and_extensions() {
+    let books = parse_fiction_results(FICTION);
+
+    assert_eq!(books[1].filesize, (620 * 1024).to_string());
+    assert_eq!(books[2].filesize, (2 * 1024 * 1024).to_string());
+    assert_eq!(books[2].extension, "pdf");
+    assert_eq!(books[2].series, "Dune Chronicles - 2");
+}
+
+#[test]
+fn fiction_author_without_list_and_rows_without_title_link() {
+    let html = r#"<table class="catalog"><tbody>
+<tr>
+<td>Le Guin, Ursula K.</td><td></td>
+<td><p><a href="/fiction/0CC175B9C0F1B6A831C399E269772661">The Dispossessed</a></p></td>
+<td>English</td><td>EPUB / 508&nbsp;Kb</td><td></td>
+</tr>
+<tr>
+<td>Nobody</td><td></td><td>No link here</td><td>English</td><td>EPUB / 1 Mb</td><td></td>
+</tr>
+</tbody></table>"#;
+    let books = parse_fiction_results(html);
+
+    assert_eq!(books.len(), 1);
+    assert_eq!(books[0].author, "Le Guin, Ursula K.");
+    assert_eq!(books[0].title, "The Dispossessed");
+    assert_eq!(books[0].filesize, (508 * 1024).to_string());
+}
+
+#[test]
+fn fiction_page_without_results() {
+    assert!(parse_fiction_results("<html><body>No files were found</body></html>").is_empty());
+}